@@ -1,10 +1,12 @@
 use anyhow::Result;
 
+#[allow(dead_code)]
 pub fn install_bootloader(_root: &str) -> Result<()> {
     // TODO: Implement UEFI + Apple Silicon boot logic.
     Ok(())
 }
 
+#[allow(dead_code)]
 pub fn generate_boot_config(_root: &str) -> Result<()> {
     // TODO: Generate loader entries and kernel parameters.
     Ok(())
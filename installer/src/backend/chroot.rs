@@ -0,0 +1,233 @@
+use std::env;
+use std::sync::Arc;
+
+use anyhow::{bail, Result};
+
+use crate::backend::command::{
+    CancellationToken, CommandExecutor, CommandOutput, CommandSpec, OutputStream,
+};
+
+/// Environment variable used to pick the chroot backend, mainly so the
+/// configure stage can run inside containers.
+pub const CHROOT_MODE_ENV: &str = "ARM_INSTALLER_CHROOT";
+
+/// How commands are run inside the target root.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ChrootMode {
+    /// `arch-chroot`, which also mounts /proc, /sys, /dev and friends.
+    #[default]
+    ArchChroot,
+    /// `systemd-nspawn` with the target as its directory.
+    SystemdNspawn,
+    /// Plain `chroot(8)` without any mount setup, for environments where
+    /// arch-chroot is not allowed to mount. Commands still run inside the
+    /// root: run on the host, `useradd` and friends would change the live
+    /// system instead of the target.
+    Chroot,
+}
+
+impl ChrootMode {
+    pub fn parse(value: &str) -> Result<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "arch-chroot" => Ok(ChrootMode::ArchChroot),
+            "systemd-nspawn" | "nspawn" => Ok(ChrootMode::SystemdNspawn),
+            "chroot" => Ok(ChrootMode::Chroot),
+            other => bail!(
+                "unknown chroot mode {other:?}, expected arch-chroot, systemd-nspawn or chroot"
+            ),
+        }
+    }
+
+    /// Read the mode from [`CHROOT_MODE_ENV`], defaulting to arch-chroot.
+    pub fn from_env() -> Result<Self> {
+        match env::var(CHROOT_MODE_ENV) {
+            Ok(value) => Self::parse(&value),
+            Err(_) => Ok(ChrootMode::default()),
+        }
+    }
+
+    /// Host program that enters the target root.
+    pub fn program(&self) -> &'static str {
+        match self {
+            ChrootMode::ArchChroot => "arch-chroot",
+            ChrootMode::SystemdNspawn => "systemd-nspawn",
+            ChrootMode::Chroot => "chroot",
+        }
+    }
+
+    /// Turn a command written relative to the target into the host command
+    /// that runs it inside `root`.
+    pub fn wrap(&self, root: &str, spec: &CommandSpec) -> CommandSpec {
+        let mut wrapped = spec.clone();
+        wrapped.program = self.program().into();
+        let mut args = Vec::with_capacity(spec.args.len() + 6);
+
+        match self {
+            ChrootMode::ArchChroot | ChrootMode::Chroot => {
+                args.push(root.to_string());
+                // Both chroot flavours start in `/`; the host working
+                // directory would be meaningless inside the target.
+                if let Some(dir) = wrapped.current_dir.take() {
+                    args.push("env".into());
+                    args.push("-C".into());
+                    args.push(dir.to_string_lossy().into_owned());
+                }
+            }
+            ChrootMode::SystemdNspawn => {
+                args.push("--quiet".into());
+                args.push("--console=pipe".into());
+                args.push(format!("--directory={root}"));
+                // nspawn does not pass the host environment through.
+                for (key, value) in wrapped.env.drain(..) {
+                    args.push(format!("--setenv={key}={value}"));
+                }
+                if let Some(dir) = wrapped.current_dir.take() {
+                    args.push(format!("--chdir={}", dir.display()));
+                }
+            }
+        }
+
+        args.push(spec.program.clone());
+        args.extend(spec.args.iter().cloned());
+        wrapped.args = args;
+        wrapped
+    }
+}
+
+/// Runs commands inside a target root through another executor.
+pub struct ChrootExecutor {
+    inner: Arc<dyn CommandExecutor + Send + Sync>,
+    root: String,
+    mode: ChrootMode,
+}
+
+impl ChrootExecutor {
+    pub fn new(
+        inner: Arc<dyn CommandExecutor + Send + Sync>,
+        root: impl Into<String>,
+        mode: ChrootMode,
+    ) -> Self {
+        Self {
+            inner,
+            root: root.into(),
+            mode,
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn root(&self) -> &str {
+        &self.root
+    }
+
+    #[allow(dead_code)]
+    pub fn mode(&self) -> ChrootMode {
+        self.mode
+    }
+
+    pub fn wrap(&self, spec: &CommandSpec) -> CommandSpec {
+        self.mode.wrap(&self.root, spec)
+    }
+}
+
+impl CommandExecutor for ChrootExecutor {
+    fn run(&self, spec: &CommandSpec) -> Result<CommandOutput> {
+        self.inner.run(&self.wrap(spec))
+    }
+
+    fn run_streaming(
+        &self,
+        spec: &CommandSpec,
+        cancel: &CancellationToken,
+        on_line: &mut dyn FnMut(OutputStream, &str),
+    ) -> Result<CommandOutput> {
+        self.inner.run_streaming(&self.wrap(spec), cancel, on_line)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec() -> CommandSpec {
+        CommandSpec::new("useradd", vec!["-m".into(), "armuser".into()])
+            .env("LANG", "C")
+            .current_dir("/home")
+    }
+
+    #[test]
+    fn chroot_modes_enter_the_root_and_move_the_working_directory() {
+        for mode in [ChrootMode::ArchChroot, ChrootMode::Chroot] {
+            let wrapped = mode.wrap("/mnt/target", &spec());
+
+            assert_eq!(wrapped.program, mode.program());
+            assert_eq!(
+                wrapped.args,
+                vec![
+                    "/mnt/target",
+                    "env",
+                    "-C",
+                    "/home",
+                    "useradd",
+                    "-m",
+                    "armuser"
+                ]
+            );
+            assert_eq!(wrapped.current_dir, None);
+            assert_eq!(wrapped.env, vec![("LANG".to_string(), "C".to_string())]);
+        }
+    }
+
+    #[test]
+    fn nspawn_passes_environment_and_directory_as_flags() {
+        let wrapped = ChrootMode::SystemdNspawn.wrap("/mnt/target", &spec());
+
+        assert_eq!(wrapped.program, "systemd-nspawn");
+        assert_eq!(
+            wrapped.args,
+            vec![
+                "--quiet",
+                "--console=pipe",
+                "--directory=/mnt/target",
+                "--setenv=LANG=C",
+                "--chdir=/home",
+                "useradd",
+                "-m",
+                "armuser",
+            ]
+        );
+        assert!(wrapped.env.is_empty());
+        assert_eq!(wrapped.current_dir, None);
+    }
+
+    #[test]
+    fn commands_without_a_directory_are_wrapped_as_is() {
+        let plain = CommandSpec::new("locale-gen", Vec::new());
+
+        assert_eq!(
+            ChrootMode::ArchChroot.wrap("/mnt/target", &plain).args,
+            vec!["/mnt/target", "locale-gen"]
+        );
+    }
+
+    #[test]
+    fn mode_is_parsed_from_the_environment() {
+        for (value, expected) in [
+            ("arch-chroot", ChrootMode::ArchChroot),
+            (" Systemd-Nspawn ", ChrootMode::SystemdNspawn),
+            ("nspawn", ChrootMode::SystemdNspawn),
+            ("chroot", ChrootMode::Chroot),
+        ] {
+            assert_eq!(ChrootMode::parse(value).unwrap(), expected, "{value:?}");
+        }
+        assert!(ChrootMode::parse("direct").is_err());
+        assert!(ChrootMode::parse("").is_err());
+
+        env::remove_var(CHROOT_MODE_ENV);
+        assert_eq!(ChrootMode::from_env().unwrap(), ChrootMode::ArchChroot);
+        env::set_var(CHROOT_MODE_ENV, "nspawn");
+        assert_eq!(ChrootMode::from_env().unwrap(), ChrootMode::SystemdNspawn);
+        env::set_var(CHROOT_MODE_ENV, "bogus");
+        assert!(ChrootMode::from_env().is_err());
+        env::remove_var(CHROOT_MODE_ENV);
+    }
+}
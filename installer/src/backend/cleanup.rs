@@ -1,10 +1,12 @@
 use anyhow::Result;
 
+#[allow(dead_code)]
 pub fn teardown_mounts(_root: &str) -> Result<()> {
     // TODO: Unmount filesystems and disable swap.
     Ok(())
 }
 
+#[allow(dead_code)]
 pub fn sync_and_reboot() -> Result<()> {
     // TODO: Call sync and schedule reboot.
     Ok(())
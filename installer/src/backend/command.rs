@@ -1,12 +1,120 @@
-use std::process::{Command, ExitStatus, Stdio};
+use std::fmt;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use anyhow::{Context, Result};
 use serde::de::DeserializeOwned;
+use thiserror::Error;
+use tracing::warn;
+
+/// How often a running command is checked for cancellation or timeout.
+pub const SUPERVISE_INTERVAL: Duration = Duration::from_millis(100);
+/// Time a process group gets to exit after SIGTERM before it is killed.
+pub const TERMINATE_GRACE: Duration = Duration::from_secs(5);
 
 #[derive(Debug, Clone)]
 pub struct CommandSpec {
     pub program: String,
     pub args: Vec<String>,
+    pub timeout: Option<Duration>,
+    /// Extra environment variables layered over the inherited environment.
+    pub env: Vec<(String, String)>,
+    pub current_dir: Option<PathBuf>,
+    pub stdin: Option<StdinPayload>,
+    pub retry: Option<RetryPolicy>,
+    /// Exit codes other than 0 that still count as success.
+    pub extra_success_codes: Vec<i32>,
+    /// Commands run when this one fails, times out or is cancelled, to undo
+    /// what it left behind.
+    pub on_failure: Vec<CommandSpec>,
+}
+
+/// Describes how often and when a failing command is run again.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Total number of attempts, including the first one.
+    pub max_attempts: u32,
+    pub backoff: Backoff,
+    /// Exit codes worth retrying. An empty list treats every non-zero exit
+    /// code as retryable.
+    pub retryable_exit_codes: Vec<i32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backoff {
+    #[allow(dead_code)]
+    Fixed(Duration),
+    /// Start at `initial` and double after every failed attempt, up to `max`.
+    Exponential { initial: Duration, max: Duration },
+}
+
+impl RetryPolicy {
+    pub fn new(max_attempts: u32, backoff: Backoff) -> Self {
+        Self {
+            max_attempts,
+            backoff,
+            retryable_exit_codes: Vec::new(),
+        }
+    }
+
+    pub fn with_retryable_exit_codes(mut self, codes: impl Into<Vec<i32>>) -> Self {
+        self.retryable_exit_codes = codes.into();
+        self
+    }
+
+    /// Whether a command that finished with `status` on attempt number
+    /// `attempt` (1-based) should be run again.
+    pub fn should_retry(&self, attempt: u32, status: &ExitStatus) -> bool {
+        if status.success() || attempt >= self.max_attempts {
+            return false;
+        }
+
+        match status.code() {
+            Some(code) => {
+                self.retryable_exit_codes.is_empty() || self.retryable_exit_codes.contains(&code)
+            }
+            // Killed by a signal; not a transient failure.
+            None => false,
+        }
+    }
+
+    /// Delay before attempt number `attempt + 1`.
+    pub fn delay_after(&self, attempt: u32) -> Duration {
+        match self.backoff {
+            Backoff::Fixed(delay) => delay,
+            Backoff::Exponential { initial, max } => {
+                let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+                initial.saturating_mul(factor).min(max)
+            }
+        }
+    }
+}
+
+/// Bytes written to a command's stdin. The `Debug` output never shows the
+/// contents, so payloads may carry secrets such as passwords.
+#[derive(Clone)]
+pub struct StdinPayload(Vec<u8>);
+
+impl StdinPayload {
+    pub fn new(bytes: impl Into<Vec<u8>>) -> Self {
+        Self(bytes.into())
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl fmt::Debug for StdinPayload {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "StdinPayload(<{} bytes>)", self.0.len())
+    }
 }
 
 impl CommandSpec {
@@ -14,14 +122,71 @@ impl CommandSpec {
         Self {
             program: program.into(),
             args: args.into(),
+            timeout: None,
+            env: Vec::new(),
+            current_dir: None,
+            stdin: None,
+            retry: None,
+            extra_success_codes: Vec::new(),
+            on_failure: Vec::new(),
         }
     }
 
+    pub fn with_retry(mut self, policy: RetryPolicy) -> Self {
+        self.retry = Some(policy);
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn env(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.env.push((key.into(), value.into()));
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn current_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.current_dir = Some(dir.into());
+        self
+    }
+
+    pub fn stdin(mut self, payload: impl Into<Vec<u8>>) -> Self {
+        self.stdin = Some(StdinPayload::new(payload));
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Also treat `codes` as success, for tools such as `e2fsck` that report
+    /// repaired problems with a non-zero exit code.
+    pub fn with_success_codes(mut self, codes: impl Into<Vec<i32>>) -> Self {
+        self.extra_success_codes = codes.into();
+        self
+    }
+
+    /// Run `cleanup` if this command does not succeed.
+    pub fn on_failure(mut self, cleanup: CommandSpec) -> Self {
+        self.on_failure.push(cleanup);
+        self
+    }
+
+    /// Whether a command run from this spec finished successfully.
+    pub fn accepts(&self, status: &ExitStatus) -> bool {
+        status.success()
+            || status
+                .code()
+                .is_some_and(|code| self.extra_success_codes.contains(&code))
+    }
+
+    #[allow(dead_code)]
     pub fn arg(mut self, value: impl Into<String>) -> Self {
         self.args.push(value.into());
         self
     }
 
+    #[allow(dead_code)]
     pub fn with_args(mut self, values: impl IntoIterator<Item = impl Into<String>>) -> Self {
         for value in values {
             self.args.push(value.into());
@@ -37,15 +202,96 @@ pub struct CommandOutput {
     pub stdout: String,
     pub stderr: String,
     pub status: ExitStatus,
+    accepted: bool,
+}
+
+/// Shared flag used to abort running commands. Clones observe the same state.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// Reasons a command was stopped before it could exit on its own. Returned
+/// inside `anyhow::Error` so callers can tell them apart with `downcast_ref`.
+#[derive(Debug, Error)]
+pub enum CommandError {
+    #[error("{program} was cancelled")]
+    Cancelled { program: String },
+    #[error("{program} timed out after {timeout:?}")]
+    TimedOut { program: String, timeout: Duration },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputStream {
+    Stdout,
+    Stderr,
 }
 
 pub trait CommandExecutor: Send + Sync {
     fn run(&self, spec: &CommandSpec) -> Result<CommandOutput>;
+
+    /// Run `spec`, handing every output line to `on_line` as soon as it is
+    /// available. The returned output still carries the complete stdout and
+    /// stderr. Executors that cannot stream replay the buffered output.
+    ///
+    /// Cancelling `cancel` or exceeding `spec.timeout` stops the command and
+    /// yields a [`CommandError`].
+    fn run_streaming(
+        &self,
+        spec: &CommandSpec,
+        cancel: &CancellationToken,
+        on_line: &mut dyn FnMut(OutputStream, &str),
+    ) -> Result<CommandOutput> {
+        if cancel.is_cancelled() {
+            return Err(CommandError::Cancelled {
+                program: spec.program.clone(),
+            }
+            .into());
+        }
+
+        let output = self.run(spec)?;
+        for line in output.stdout.lines() {
+            on_line(OutputStream::Stdout, line);
+        }
+        for line in output.stderr.lines() {
+            on_line(OutputStream::Stderr, line);
+        }
+        Ok(output)
+    }
 }
 
 impl CommandOutput {
+    pub fn new(
+        spec: &CommandSpec,
+        stdout: impl Into<String>,
+        stderr: impl Into<String>,
+        status: ExitStatus,
+    ) -> Self {
+        Self {
+            program: spec.program.clone(),
+            args: spec.args.clone(),
+            stdout: stdout.into(),
+            stderr: stderr.into(),
+            accepted: spec.accepts(&status),
+            status,
+        }
+    }
+
+    /// Whether the command succeeded, counting the spec's extra success codes.
     pub fn success(&self) -> bool {
-        self.status.success()
+        self.accepted
     }
 
     pub fn program(&self) -> &str {
@@ -62,24 +308,216 @@ pub struct SystemCommandExecutor;
 
 impl CommandExecutor for SystemCommandExecutor {
     fn run(&self, spec: &CommandSpec) -> Result<CommandOutput> {
+        self.run_streaming(spec, &CancellationToken::new(), &mut |_, _| {})
+    }
+
+    fn run_streaming(
+        &self,
+        spec: &CommandSpec,
+        cancel: &CancellationToken,
+        on_line: &mut dyn FnMut(OutputStream, &str),
+    ) -> Result<CommandOutput> {
+        // Run each command in its own process group so that cancellation
+        // also reaches the helpers it forks (pacstrap, arch-chroot, ...).
         let mut cmd = Command::new(&spec.program);
         cmd.args(&spec.args)
-            .stdin(Stdio::null())
+            .envs(spec.env.iter().map(|(key, value)| (key, value)))
+            .stdin(if spec.stdin.is_some() {
+                Stdio::piped()
+            } else {
+                Stdio::null()
+            })
             .stdout(Stdio::piped())
-            .stderr(Stdio::piped());
+            .stderr(Stdio::piped())
+            .process_group(0);
+        if let Some(dir) = &spec.current_dir {
+            cmd.current_dir(dir);
+        }
 
-        let output = cmd
-            .output()
+        let mut child = cmd
+            .spawn()
             .with_context(|| format!("failed to execute {}", spec.program))?;
 
-        Ok(CommandOutput {
+        // Feed stdin from its own thread so a child that writes a lot of
+        // output before reading its input cannot deadlock us.
+        let writer = match (child.stdin.take(), &spec.stdin) {
+            (Some(mut stdin), Some(payload)) => {
+                let payload = payload.clone();
+                Some(thread::spawn(move || {
+                    let _ = stdin.write_all(payload.as_bytes());
+                }))
+            }
+            _ => None,
+        };
+
+        let (tx, rx) = mpsc::channel();
+        let mut readers = Vec::with_capacity(2);
+        if let Some(stdout) = child.stdout.take() {
+            readers.push(spawn_line_reader(stdout, OutputStream::Stdout, tx.clone()));
+        }
+        if let Some(stderr) = child.stderr.take() {
+            readers.push(spawn_line_reader(stderr, OutputStream::Stderr, tx.clone()));
+        }
+        // Only the reader threads hold senders now, so the loop below ends
+        // once both pipes are closed.
+        drop(tx);
+
+        let deadline = spec.timeout.map(|timeout| Instant::now() + timeout);
+        let mut stopped: Option<CommandError> = None;
+        let mut stdout = String::new();
+        let mut stderr = String::new();
+
+        loop {
+            match rx.recv_timeout(SUPERVISE_INTERVAL) {
+                Ok((stream, line)) => {
+                    on_line(stream, &line);
+                    let buffer = match stream {
+                        OutputStream::Stdout => &mut stdout,
+                        OutputStream::Stderr => &mut stderr,
+                    };
+                    buffer.push_str(&line);
+                    buffer.push('\n');
+                }
+                Err(mpsc::RecvTimeoutError::Timeout) => {}
+                Err(mpsc::RecvTimeoutError::Disconnected) => break,
+            }
+
+            if stopped.is_none() {
+                stopped = stop_reason(spec, cancel, deadline);
+                if stopped.is_some() {
+                    terminate_process_group(&mut child);
+                }
+            }
+        }
+
+        for reader in readers {
+            let _ = reader.join();
+        }
+
+        // The pipes can close long before the child exits (it may redirect
+        // or close them), so keep honouring cancellation and the timeout.
+        let status = loop {
+            let exited = child
+                .try_wait()
+                .with_context(|| format!("failed to wait for {}", spec.program))?;
+            if let Some(status) = exited {
+                break status;
+            }
+            if stopped.is_none() {
+                stopped = stop_reason(spec, cancel, deadline);
+                if stopped.is_some() {
+                    terminate_process_group(&mut child);
+                }
+            }
+            thread::sleep(SUPERVISE_INTERVAL);
+        };
+
+        // Joined only now: a child that never reads its input keeps the
+        // writer blocked until it exits.
+        if let Some(writer) = writer {
+            let _ = writer.join();
+        }
+
+        if let Some(error) = stopped {
+            return Err(error.into());
+        }
+
+        Ok(CommandOutput::new(spec, stdout, stderr, status))
+    }
+}
+
+/// Why a running command has to be stopped, if it does.
+fn stop_reason(
+    spec: &CommandSpec,
+    cancel: &CancellationToken,
+    deadline: Option<Instant>,
+) -> Option<CommandError> {
+    if cancel.is_cancelled() {
+        return Some(CommandError::Cancelled {
             program: spec.program.clone(),
-            args: spec.args.clone(),
-            stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
-            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
-            status: output.status,
-        })
+        });
+    }
+    match (deadline, spec.timeout) {
+        (Some(deadline), Some(timeout)) if Instant::now() >= deadline => {
+            Some(CommandError::TimedOut {
+                program: spec.program.clone(),
+                timeout,
+            })
+        }
+        _ => None,
+    }
+}
+
+/// Ask the child's process group to exit, then SIGKILL whatever is left of
+/// the group once the child is gone or [`TERMINATE_GRACE`] has passed.
+fn terminate_process_group(child: &mut Child) {
+    if !signal_process_group(child.id(), libc::SIGTERM) {
+        let _ = child.kill();
+        return;
+    }
+
+    let deadline = Instant::now() + TERMINATE_GRACE;
+    while Instant::now() < deadline {
+        if let Ok(Some(_)) = child.try_wait() {
+            break;
+        }
+        thread::sleep(SUPERVISE_INTERVAL);
+    }
+
+    // Stragglers that ignored SIGTERM would otherwise keep the output pipes
+    // open. Once the whole group is gone there is nothing left to kill.
+    if !signal_process_group(child.id(), libc::SIGKILL) {
+        let _ = child.kill();
+    }
+}
+
+/// Send `signal` to the process group led by `pid`. Returns whether the
+/// signal was delivered; failures other than the group being gone already
+/// are logged.
+pub fn signal_process_group(pid: u32, signal: libc::c_int) -> bool {
+    let Ok(pgid) = libc::pid_t::try_from(pid) else {
+        warn!(pid, "pid cannot be addressed as a process group");
+        return false;
+    };
+
+    // SAFETY: kill(2) has no memory-safety preconditions; a negative pid
+    // addresses the process group created for the child.
+    if unsafe { libc::kill(-pgid, signal) } == 0 {
+        return true;
     }
+
+    let err = std::io::Error::last_os_error();
+    if err.raw_os_error() != Some(libc::ESRCH) {
+        warn!(pid, signal, "failed to signal process group: {}", err);
+    }
+    false
+}
+
+fn spawn_line_reader<R>(
+    source: R,
+    stream: OutputStream,
+    tx: mpsc::Sender<(OutputStream, String)>,
+) -> thread::JoinHandle<()>
+where
+    R: Read + Send + 'static,
+{
+    thread::spawn(move || {
+        let mut reader = BufReader::new(source);
+        let mut buf = Vec::new();
+        loop {
+            buf.clear();
+            match reader.read_until(b'\n', &mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {
+                    let line = String::from_utf8_lossy(&buf);
+                    let line = line.trim_end_matches(['\n', '\r']).to_string();
+                    if tx.send((stream, line)).is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+    })
 }
 
 pub fn run_command_json<T>(executor: &dyn CommandExecutor, spec: &CommandSpec) -> Result<T>
@@ -95,3 +533,112 @@ where
     })?;
     Ok(value)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timeout_applies_after_the_child_closes_its_output() {
+        let spec = CommandSpec::new(
+            "sh",
+            vec!["-c".into(), "exec >/dev/null 2>&1; sleep 30".into()],
+        )
+        .with_timeout(Duration::from_millis(300));
+
+        let started = Instant::now();
+        let err = SystemCommandExecutor.run(&spec).unwrap_err();
+
+        assert!(matches!(
+            err.downcast_ref::<CommandError>(),
+            Some(CommandError::TimedOut { .. })
+        ));
+        assert!(started.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn cancel_applies_after_the_child_closes_its_output() {
+        let spec = CommandSpec::new(
+            "sh",
+            vec!["-c".into(), "exec >/dev/null 2>&1; sleep 30".into()],
+        );
+        let cancel = CancellationToken::new();
+        let canceller = cancel.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(300));
+            canceller.cancel();
+        });
+
+        let err = SystemCommandExecutor
+            .run_streaming(&spec, &cancel, &mut |_, _| {})
+            .unwrap_err();
+
+        assert!(matches!(
+            err.downcast_ref::<CommandError>(),
+            Some(CommandError::Cancelled { .. })
+        ));
+    }
+
+    #[test]
+    fn signalling_a_missing_group_reports_failure() {
+        let mut child = Command::new("true").process_group(0).spawn().unwrap();
+        let pid = child.id();
+        child.wait().unwrap();
+
+        assert!(!signal_process_group(pid, 0));
+    }
+
+    #[test]
+    fn extra_success_codes_count_as_success() {
+        let exit = |code: i32| {
+            CommandSpec::new("sh", vec!["-c".into(), format!("exit {code}")])
+                .with_success_codes([1])
+        };
+
+        assert!(SystemCommandExecutor.run(&exit(0)).unwrap().success());
+        assert!(SystemCommandExecutor.run(&exit(1)).unwrap().success());
+        assert!(!SystemCommandExecutor.run(&exit(2)).unwrap().success());
+    }
+
+    #[test]
+    fn env_working_directory_and_stdin_reach_the_child() {
+        let dir = std::env::temp_dir().canonicalize().unwrap();
+        let spec = CommandSpec::new("sh", Vec::new())
+            .with_args(["-c", "printf '%s\\n' \"$FOO\"; pwd; cat"])
+            .env("FOO", "bar baz")
+            .current_dir(&dir)
+            .stdin("from stdin\n");
+
+        let output = SystemCommandExecutor.run(&spec).unwrap();
+
+        assert!(output.success());
+        assert_eq!(
+            output.stdout,
+            format!("bar baz\n{}\nfrom stdin\n", dir.display())
+        );
+    }
+
+    #[test]
+    fn large_stdin_does_not_block_on_the_child_output() {
+        let payload = format!("{}\n", "x".repeat(1023)).repeat(1024);
+        let spec = CommandSpec::new("cat", Vec::new())
+            .arg("-")
+            .stdin(payload.clone())
+            .with_timeout(Duration::from_secs(30));
+
+        let output = SystemCommandExecutor.run(&spec).unwrap();
+
+        assert_eq!(output.stdout, payload);
+    }
+
+    #[test]
+    fn stdin_payload_debug_hides_the_contents() {
+        let spec = CommandSpec::new("chpasswd", Vec::new()).stdin("armuser:secret\n");
+
+        assert_eq!(
+            format!("{:?}", spec.stdin.as_ref().unwrap()),
+            "StdinPayload(<15 bytes>)"
+        );
+        assert!(!format!("{spec:?}").contains("secret"));
+    }
+}
@@ -0,0 +1,191 @@
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::process::{Child, Command};
+use tokio::task::JoinSet;
+
+use crate::backend::command::{
+    signal_process_group, CancellationToken, CommandError, CommandOutput, CommandSpec,
+    SUPERVISE_INTERVAL, TERMINATE_GRACE,
+};
+
+/// Async counterpart of [`CommandExecutor`](crate::backend::command::CommandExecutor), used
+/// to run independent commands side by side.
+#[async_trait]
+pub trait AsyncCommandExecutor: Send + Sync {
+    async fn run(&self, spec: &CommandSpec, cancel: &CancellationToken) -> Result<CommandOutput>;
+}
+
+/// Spawns processes through tokio. Honours the same `CommandSpec` options as
+/// [`SystemCommandExecutor`](crate::backend::command::SystemCommandExecutor).
+#[derive(Debug, Default, Clone)]
+pub struct TokioCommandExecutor;
+
+#[async_trait]
+impl AsyncCommandExecutor for TokioCommandExecutor {
+    async fn run(&self, spec: &CommandSpec, cancel: &CancellationToken) -> Result<CommandOutput> {
+        let mut cmd = Command::new(&spec.program);
+        cmd.args(&spec.args)
+            .envs(spec.env.iter().map(|(key, value)| (key, value)))
+            .stdin(if spec.stdin.is_some() {
+                Stdio::piped()
+            } else {
+                Stdio::null()
+            })
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .process_group(0)
+            .kill_on_drop(true);
+        if let Some(dir) = &spec.current_dir {
+            cmd.current_dir(dir);
+        }
+
+        let mut child = cmd
+            .spawn()
+            .with_context(|| format!("failed to execute {}", spec.program))?;
+
+        if let (Some(mut stdin), Some(payload)) = (child.stdin.take(), spec.stdin.clone()) {
+            tokio::spawn(async move {
+                let _ = stdin.write_all(payload.as_bytes()).await;
+            });
+        }
+        let stdout = child
+            .stdout
+            .take()
+            .map(|pipe| tokio::spawn(read_to_string(pipe)));
+        let stderr = child
+            .stderr
+            .take()
+            .map(|pipe| tokio::spawn(read_to_string(pipe)));
+
+        let deadline = spec.timeout.map(|timeout| Instant::now() + timeout);
+        let status = loop {
+            tokio::select! {
+                status = child.wait() => {
+                    break status.with_context(|| format!("failed to wait for {}", spec.program))?;
+                }
+                _ = tokio::time::sleep(SUPERVISE_INTERVAL) => {}
+            }
+
+            let stopped = if cancel.is_cancelled() {
+                Some(CommandError::Cancelled {
+                    program: spec.program.clone(),
+                })
+            } else {
+                match (deadline, spec.timeout) {
+                    (Some(deadline), Some(timeout)) if Instant::now() >= deadline => {
+                        Some(CommandError::TimedOut {
+                            program: spec.program.clone(),
+                            timeout,
+                        })
+                    }
+                    _ => None,
+                }
+            };
+
+            if let Some(error) = stopped {
+                terminate_process_group(&mut child).await;
+                return Err(error.into());
+            }
+        };
+
+        Ok(CommandOutput::new(
+            spec,
+            join_output(stdout).await,
+            join_output(stderr).await,
+            status,
+        ))
+    }
+}
+
+/// Result of one command from [`run_concurrent`].
+#[derive(Debug)]
+pub struct CompletedCommand {
+    pub spec: CommandSpec,
+    pub started_at: SystemTime,
+    pub duration: Duration,
+    pub result: Result<CommandOutput>,
+}
+
+/// Run commands that do not depend on each other at the same time. Results
+/// come back in the order of `specs`, regardless of completion order.
+/// Retry policies are not applied here.
+pub async fn run_concurrent(
+    executor: Arc<dyn AsyncCommandExecutor>,
+    specs: Vec<CommandSpec>,
+    cancel: CancellationToken,
+) -> Vec<CompletedCommand> {
+    let mut tasks = JoinSet::new();
+    for (index, spec) in specs.iter().cloned().enumerate() {
+        let executor = Arc::clone(&executor);
+        let cancel = cancel.clone();
+        tasks.spawn(async move {
+            let started_at = SystemTime::now();
+            let clock = Instant::now();
+            let result = executor.run(&spec, &cancel).await;
+            (
+                index,
+                CompletedCommand {
+                    spec,
+                    started_at,
+                    duration: clock.elapsed(),
+                    result,
+                },
+            )
+        });
+    }
+
+    let mut completed: Vec<Option<CompletedCommand>> = specs.iter().map(|_| None).collect();
+    while let Some(joined) = tasks.join_next().await {
+        if let Ok((index, command)) = joined {
+            completed[index] = Some(command);
+        }
+    }
+
+    completed
+        .into_iter()
+        .zip(specs)
+        .map(|(command, spec)| {
+            command.unwrap_or_else(|| CompletedCommand {
+                result: Err(anyhow!("task running {} panicked", spec.program)),
+                spec,
+                started_at: SystemTime::now(),
+                duration: Duration::ZERO,
+            })
+        })
+        .collect()
+}
+
+async fn terminate_process_group(child: &mut Child) {
+    let Some(pid) = child.id() else {
+        return;
+    };
+
+    if !signal_process_group(pid, libc::SIGTERM) {
+        let _ = child.kill().await;
+        return;
+    }
+    let _ = tokio::time::timeout(TERMINATE_GRACE, child.wait()).await;
+    signal_process_group(pid, libc::SIGKILL);
+    let _ = child.wait().await;
+}
+
+async fn read_to_string<R>(mut source: R) -> String
+where
+    R: AsyncRead + Unpin,
+{
+    let mut buf = Vec::new();
+    let _ = source.read_to_end(&mut buf).await;
+    String::from_utf8_lossy(&buf).into_owned()
+}
+
+async fn join_output(handle: Option<tokio::task::JoinHandle<String>>) -> String {
+    match handle {
+        Some(handle) => handle.await.unwrap_or_default(),
+        None => String::new(),
+    }
+}
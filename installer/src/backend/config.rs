@@ -2,6 +2,7 @@ use anyhow::{anyhow, Result};
 
 use crate::state::{InstallerState, NetworkConfig};
 
+#[allow(dead_code)]
 pub fn apply_locale(_root: &str, state: &InstallerState) -> Result<()> {
     if state.locale.language.is_empty() {
         return Err(anyhow!("locale language cannot be empty"));
@@ -12,6 +13,7 @@ pub fn apply_locale(_root: &str, state: &InstallerState) -> Result<()> {
     Ok(())
 }
 
+#[allow(dead_code)]
 pub fn configure_network(_root: &str, network: &NetworkConfig) -> Result<()> {
     if network.hostname.is_empty() {
         return Err(anyhow!("hostname cannot be empty"));
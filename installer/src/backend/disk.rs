@@ -1,42 +1,415 @@
-use anyhow::{Context, Result};
-use serde::Deserialize;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Deserializer};
+use tracing::warn;
 
 use crate::backend::command::{
     run_command_json, CommandExecutor, CommandSpec, SystemCommandExecutor,
 };
-use crate::state::DiskIdentifier;
+use crate::state::{
+    BlockNode, DiskDevice, DiskGeometry, DiskIdentifier, DiskInventory, FreeSpaceRegion,
+    Ineligibility,
+};
+
+/// udev's directory of persistent disk names.
+pub const BY_ID_DIR: &str = "/dev/disk/by-id";
+
+const LSBLK_TIMEOUT: Duration = Duration::from_secs(30);
+const SFDISK_TIMEOUT: Duration = Duration::from_secs(30);
+
+const MIB: u64 = 1024 * 1024;
+
+/// Gaps smaller than this are left alone; they are usually alignment slack
+/// between partitions.
+pub const MIN_FREE_REGION_BYTES: u64 = 1024 * MIB;
+
+/// Smallest disk the default layout (ESP plus base and desktop packages)
+/// comfortably fits on.
+pub const MIN_TARGET_BYTES: u64 = 16 * 1024 * 1024 * 1024;
+
+/// Mount points used by common live environments for the boot medium.
+const LIVE_MEDIUM_MOUNTS: &[&str] = &[
+    "/run/archiso/bootmnt",
+    "/run/initramfs/live",
+    "/run/live/medium",
+    "/cdrom",
+];
+
+const LSBLK_COLUMNS: &str =
+    "NAME,PATH,SIZE,MODEL,TYPE,FSTYPE,LABEL,PARTLABEL,PARTTYPE,UUID,PARTUUID,MOUNTPOINTS,RO,RM,TRAN,PTTYPE,LOG-SEC,PHY-SEC,OPT-IO,DISC-GRAN,ROTA,SERIAL,WWN,START";
 
+#[allow(dead_code)]
 pub fn probe_block_devices() -> Result<Vec<DiskIdentifier>> {
-    let executor = SystemCommandExecutor::default();
+    let executor = SystemCommandExecutor;
     probe_block_devices_with(&executor)
 }
 
 pub fn probe_block_devices_with(executor: &dyn CommandExecutor) -> Result<Vec<DiskIdentifier>> {
-    let spec = CommandSpec::new(
+    Ok(probe_inventory_with(executor)?.identifiers())
+}
+
+pub fn probe_inventory_with(executor: &dyn CommandExecutor) -> Result<DiskInventory> {
+    let spec = probe_command();
+    let output: LsblkOutput =
+        run_command_json(executor, &spec).context("failed to probe block devices with lsblk")?;
+
+    let disks = output
+        .blockdevices
+        .into_iter()
+        .filter(|device| device.r#type == "disk")
+        .map(disk_from_lsblk)
+        .map(|mut disk| {
+            disk.ineligible = classify(&disk);
+            disk.identifier.by_id = find_by_id(Path::new(BY_ID_DIR), &disk.identifier.path);
+            disk
+        })
+        .collect();
+
+    Ok(DiskInventory { disks })
+}
+
+/// The lsblk invocation used to discover disks.
+pub fn probe_command() -> CommandSpec {
+    CommandSpec::new(
         "lsblk",
         vec![
             "--json".to_string(),
             "--bytes".to_string(),
-            "-d".to_string(),
             "-o".to_string(),
-            "NAME,SIZE,MODEL,TYPE".to_string(),
+            LSBLK_COLUMNS.to_string(),
         ],
-    );
-    let output: LsblkOutput =
-        run_command_json(executor, &spec).context("failed to probe block devices with lsblk")?;
+    )
+    .with_timeout(LSBLK_TIMEOUT)
+}
 
-    let devices = output
-        .blockdevices
-        .into_iter()
-        .filter(|device| device.r#type == "disk")
-        .map(|device| DiskIdentifier {
-            path: format!("/dev/{}", device.name),
-            size_bytes: device.size,
-            label: device.model.filter(|s| !s.trim().is_empty()),
+/// The `/dev/disk/by-id` link for `disk_path`. Model/serial based names
+/// are preferred over `wwn-` and `nvme-eui.` ones as they are readable.
+pub fn find_by_id(by_id_dir: &Path, disk_path: &str) -> Option<String> {
+    let target = fs::canonicalize(disk_path).ok()?;
+    let mut links: Vec<String> = fs::read_dir(by_id_dir)
+        .ok()?
+        .flatten()
+        .filter(|entry| !entry.file_name().to_string_lossy().contains("-part"))
+        .filter(|entry| fs::canonicalize(entry.path()).is_ok_and(|path| path == target))
+        .map(|entry| entry.path().to_string_lossy().into_owned())
+        .collect();
+
+    let is_opaque = |link: &String| link.contains("/wwn-") || link.contains("/nvme-eui.");
+    links.sort_by_key(|link| (is_opaque(link), link.clone()));
+    links.into_iter().next()
+}
+
+/// Find the disk that was selected as `expected` among the attached disks.
+/// Disks with a by-id link, WWN or serial are found by it even when their
+/// kernel name has changed; others must still be at `expected.path` with the
+/// same size. Fails when the disk is gone, when more than one disk matches,
+/// or when it changed size.
+pub fn resolve_identity(
+    expected: &DiskIdentifier,
+    inventory: &DiskInventory,
+) -> Result<DiskIdentifier> {
+    if !expected.has_stable_identity() {
+        // Nothing but the kernel name to go on; at least the size must match.
+        let Some(current) = inventory.find(&expected.path) else {
+            bail!("target disk {} is no longer present", expected.path);
+        };
+        if current.identifier.size_bytes != expected.size_bytes {
+            bail!(
+                "target disk {} changed size from {} to {} bytes",
+                expected.path,
+                expected.size_bytes,
+                current.identifier.size_bytes
+            );
+        }
+        return Ok(current.identifier.clone());
+    }
+
+    let current = match matching_disks(expected, inventory).as_slice() {
+        [] => bail!(
+            "target disk {} ({}) is no longer attached",
+            expected.path,
+            describe_identity(expected)
+        ),
+        [current] => (*current).clone(),
+        several => {
+            let paths: Vec<&str> = several.iter().map(|disk| disk.path.as_str()).collect();
+            bail!(
+                "target disk {} matches more than one disk: {}",
+                describe_identity(expected),
+                paths.join(", ")
+            );
+        }
+    };
+    if current.size_bytes != expected.size_bytes {
+        bail!(
+            "target disk {} changed size from {} to {} bytes",
+            describe_identity(expected),
+            expected.size_bytes,
+            current.size_bytes
+        );
+    }
+    Ok(current)
+}
+
+/// Attached disks with `expected`'s WWN and serial, or the disk its by-id
+/// link points at when neither was recorded.
+fn matching_disks<'a>(
+    expected: &DiskIdentifier,
+    inventory: &'a DiskInventory,
+) -> Vec<&'a DiskIdentifier> {
+    let linked = expected
+        .by_id
+        .as_ref()
+        .and_then(|link| fs::canonicalize(link).ok())
+        .map(|path| path.to_string_lossy().into_owned());
+    let same = |field: fn(&DiskIdentifier) -> &Option<String>, disk: &DiskIdentifier| {
+        field(expected).is_none() || field(expected) == field(disk)
+    };
+
+    let mut matches: Vec<&DiskIdentifier> = inventory
+        .disks
+        .iter()
+        .map(|disk| &disk.identifier)
+        .filter(|disk| {
+            if expected.wwn.is_none() && expected.serial.is_none() {
+                linked.as_deref() == Some(disk.path.as_str())
+            } else {
+                same(|disk| &disk.wwn, disk) && same(|disk| &disk.serial, disk)
+            }
         })
         .collect();
 
-    Ok(devices)
+    // Cheap USB bridges and cloned disks can share a serial; the by-id link
+    // tells them apart when it points at one of them.
+    if let Some(linked) = linked.filter(|_| matches.len() > 1) {
+        if matches.iter().any(|disk| disk.path == linked) {
+            matches.retain(|disk| disk.path == linked);
+        }
+    }
+    matches
+}
+
+/// The serial, WWN or by-id link `disk` is recognised by.
+pub fn describe_identity(disk: &DiskIdentifier) -> String {
+    let mut parts = Vec::new();
+    if let Some(serial) = &disk.serial {
+        parts.push(format!("serial {serial}"));
+    }
+    if let Some(wwn) = &disk.wwn {
+        parts.push(format!("WWN {wwn}"));
+    }
+    if parts.is_empty() {
+        parts.push(
+            disk.by_id
+                .clone()
+                .unwrap_or_else(|| "no serial or WWN".to_string()),
+        );
+    }
+    parts.join(", ")
+}
+
+/// Unallocated regions of `disk_path`'s GPT, read with `sfdisk --json`.
+/// Disks without a GPT have no usable regions.
+pub fn probe_free_space_with(
+    executor: &dyn CommandExecutor,
+    disk_path: &str,
+) -> Result<Vec<FreeSpaceRegion>> {
+    let spec = free_space_command(disk_path);
+    let output: SfdiskOutput = run_command_json(executor, &spec)
+        .with_context(|| format!("failed to read partition table of {disk_path}"))?;
+
+    Ok(free_regions(&output.partitiontable))
+}
+
+/// The sfdisk invocation used to read a partition table.
+pub fn free_space_command(disk_path: &str) -> CommandSpec {
+    CommandSpec::new("sfdisk", vec!["--json".into(), disk_path.into()]).with_timeout(SFDISK_TIMEOUT)
+}
+
+/// Fill in `free_regions` for eligible disks that carry a GPT.
+pub fn attach_free_space(inventory: &mut DiskInventory, executor: &dyn CommandExecutor) {
+    for disk in &mut inventory.disks {
+        if !disk.is_eligible() || disk.partition_table.as_deref() != Some("gpt") {
+            continue;
+        }
+        match probe_free_space_with(executor, &disk.identifier.path) {
+            Ok(regions) => disk.free_regions = regions,
+            Err(err) => warn!(
+                path = disk.identifier.path,
+                "failed to probe free space: {:#}", err
+            ),
+        }
+    }
+}
+
+fn free_regions(table: &SfdiskTable) -> Vec<FreeSpaceRegion> {
+    if table.label != "gpt" {
+        return Vec::new();
+    }
+    let (Some(first_lba), Some(last_lba)) = (table.firstlba, table.lastlba) else {
+        return Vec::new();
+    };
+    let sector = table.sectorsize;
+
+    let mut partitions: Vec<&SfdiskPartition> = table.partitions.iter().collect();
+    partitions.sort_by_key(|partition| partition.start);
+    let used_numbers: Vec<u32> = table
+        .partitions
+        .iter()
+        .filter_map(|partition| partition_number(&partition.node))
+        .collect();
+
+    let mut gaps = Vec::new();
+    let mut cursor = first_lba;
+    for partition in partitions {
+        if partition.start > cursor {
+            gaps.push((cursor, partition.start));
+        }
+        cursor = cursor.max(partition.start + partition.size);
+    }
+    if last_lba + 1 > cursor {
+        gaps.push((cursor, last_lba + 1));
+    }
+
+    gaps.into_iter()
+        .filter_map(|(start, end)| {
+            let start_bytes = (start * sector).next_multiple_of(MIB);
+            let end_bytes = (end * sector) / MIB * MIB;
+            (end_bytes > start_bytes && end_bytes - start_bytes >= MIN_FREE_REGION_BYTES).then(
+                || FreeSpaceRegion {
+                    start_bytes,
+                    end_bytes,
+                    used_numbers: used_numbers.clone(),
+                },
+            )
+        })
+        .collect()
+}
+
+/// Trailing number of a partition node, e.g. 3 for `/dev/nvme0n1p3`.
+pub fn partition_number(node: &str) -> Option<u32> {
+    let digits = node.len() - node.trim_end_matches(|c: char| c.is_ascii_digit()).len();
+    node[node.len() - digits..].parse().ok()
+}
+
+fn disk_from_lsblk(device: LsblkDevice) -> DiskDevice {
+    let path = device_path(&device);
+    let geometry = geometry_from_lsblk(&device);
+    DiskDevice {
+        identifier: DiskIdentifier {
+            path,
+            size_bytes: device.size,
+            label: non_empty(device.model),
+            geometry,
+            serial: non_empty(device.serial),
+            wwn: non_empty(device.wwn),
+            by_id: None,
+        },
+        transport: non_empty(device.tran),
+        read_only: device.ro,
+        removable: device.rm,
+        partition_table: non_empty(device.pttype),
+        filesystem: non_empty(device.fstype),
+        mountpoints: mountpoints(device.mountpoints),
+        partitions: device.children.into_iter().map(node_from_lsblk).collect(),
+        ineligible: None,
+        existing_systems: Vec::new(),
+        free_regions: Vec::new(),
+    }
+}
+
+/// Decide whether a disk may be offered as an install target. The first
+/// matching reason wins, most specific first.
+pub fn classify(disk: &DiskDevice) -> Option<Ineligibility> {
+    let name = disk.identifier.path.rsplit('/').next().unwrap_or_default();
+    if name.starts_with("zram") {
+        return Some(Ineligibility::Zram);
+    }
+    if is_live_medium(disk) {
+        return Some(Ineligibility::LiveMedium);
+    }
+    if disk.read_only {
+        return Some(Ineligibility::ReadOnly);
+    }
+    if disk.identifier.size_bytes < MIN_TARGET_BYTES {
+        return Some(Ineligibility::TooSmall {
+            minimum_bytes: MIN_TARGET_BYTES,
+        });
+    }
+    if disk.is_mounted() {
+        return Some(Ineligibility::Mounted);
+    }
+    None
+}
+
+fn is_live_medium(disk: &DiskDevice) -> bool {
+    let is_live_mount = |mountpoint: &String| {
+        LIVE_MEDIUM_MOUNTS
+            .iter()
+            .any(|live| mountpoint == live || mountpoint.starts_with(&format!("{live}/")))
+    };
+    // Hybrid ISO images carry an iso9660 filesystem on the disk itself or
+    // on their first partition.
+    let is_iso = |filesystem: Option<&String>| filesystem.is_some_and(|fs| fs == "iso9660");
+
+    disk.mountpoints.iter().any(is_live_mount)
+        || is_iso(disk.filesystem.as_ref())
+        || disk.descendants().iter().any(|node| {
+            node.mountpoints.iter().any(is_live_mount) || is_iso(node.filesystem.as_ref())
+        })
+}
+
+fn geometry_from_lsblk(device: &LsblkDevice) -> DiskGeometry {
+    let defaults = DiskGeometry::default();
+    let logical = device.log_sec.unwrap_or(defaults.logical_sector_bytes);
+    DiskGeometry {
+        logical_sector_bytes: logical,
+        physical_sector_bytes: device.phy_sec.unwrap_or(logical),
+        optimal_io_bytes: device.opt_io.unwrap_or(0),
+        discard_granularity_bytes: device.disc_gran.unwrap_or(0),
+        rotational: device.rota,
+    }
+}
+
+fn node_from_lsblk(device: LsblkDevice) -> BlockNode {
+    BlockNode {
+        path: device_path(&device),
+        name: device.name,
+        kind: device.r#type,
+        size_bytes: device.size,
+        filesystem: non_empty(device.fstype),
+        label: non_empty(device.label),
+        partlabel: non_empty(device.partlabel),
+        parttype: non_empty(device.parttype),
+        uuid: non_empty(device.uuid),
+        partuuid: non_empty(device.partuuid),
+        mountpoints: mountpoints(device.mountpoints),
+        read_only: device.ro,
+        start_bytes: device.start.map(|start| start * 512),
+        children: device.children.into_iter().map(node_from_lsblk).collect(),
+    }
+}
+
+fn device_path(device: &LsblkDevice) -> String {
+    device
+        .path
+        .clone()
+        .unwrap_or_else(|| format!("/dev/{}", device.name))
+}
+
+fn non_empty(value: Option<String>) -> Option<String> {
+    value.filter(|s| !s.trim().is_empty())
+}
+
+fn mountpoints(values: Vec<Option<String>>) -> Vec<String> {
+    values
+        .into_iter()
+        .flatten()
+        .filter(|m| !m.is_empty())
+        .collect()
 }
 
 #[derive(Debug, Deserialize)]
@@ -48,9 +421,376 @@ struct LsblkOutput {
 #[derive(Debug, Deserialize)]
 struct LsblkDevice {
     name: String,
+    #[serde(default)]
+    path: Option<String>,
     size: u64,
     #[serde(default)]
     model: Option<String>,
     #[serde(rename = "type")]
     r#type: String,
+    #[serde(default)]
+    fstype: Option<String>,
+    #[serde(default)]
+    label: Option<String>,
+    #[serde(default)]
+    partlabel: Option<String>,
+    #[serde(default)]
+    parttype: Option<String>,
+    #[serde(default)]
+    uuid: Option<String>,
+    #[serde(default)]
+    partuuid: Option<String>,
+    #[serde(default)]
+    mountpoints: Vec<Option<String>>,
+    #[serde(default, deserialize_with = "lsblk_bool")]
+    ro: bool,
+    #[serde(default, deserialize_with = "lsblk_bool")]
+    rm: bool,
+    #[serde(default)]
+    tran: Option<String>,
+    #[serde(default)]
+    pttype: Option<String>,
+    #[serde(default, rename = "log-sec")]
+    log_sec: Option<u64>,
+    #[serde(default, rename = "phy-sec")]
+    phy_sec: Option<u64>,
+    #[serde(default, rename = "opt-io")]
+    opt_io: Option<u64>,
+    #[serde(default, rename = "disc-gran")]
+    disc_gran: Option<u64>,
+    #[serde(default, deserialize_with = "lsblk_bool")]
+    rota: bool,
+    #[serde(default)]
+    serial: Option<String>,
+    #[serde(default)]
+    wwn: Option<String>,
+    /// Partition offset in 512-byte units, whatever the sector size.
+    #[serde(default)]
+    start: Option<u64>,
+    #[serde(default)]
+    children: Vec<LsblkDevice>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SfdiskOutput {
+    partitiontable: SfdiskTable,
+}
+
+#[derive(Debug, Deserialize)]
+struct SfdiskTable {
+    label: String,
+    #[serde(default)]
+    firstlba: Option<u64>,
+    #[serde(default)]
+    lastlba: Option<u64>,
+    #[serde(default = "default_sector_size")]
+    sectorsize: u64,
+    #[serde(default)]
+    partitions: Vec<SfdiskPartition>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SfdiskPartition {
+    node: String,
+    start: u64,
+    size: u64,
+}
+
+fn default_sector_size() -> u64 {
+    512
+}
+
+/// Older util-linux releases print flags as `"0"`/`"1"` strings rather than
+/// JSON booleans.
+fn lsblk_bool<'de, D>(deserializer: D) -> std::result::Result<bool, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Flag {
+        Bool(bool),
+        Number(u8),
+        Text(String),
+    }
+
+    Ok(match Option::<Flag>::deserialize(deserializer)? {
+        Some(Flag::Bool(value)) => value,
+        Some(Flag::Number(value)) => value != 0,
+        Some(Flag::Text(value)) => value == "1" || value.eq_ignore_ascii_case("true"),
+        None => false,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::scripted::{CommandMatcher, ScriptedExecutor, ScriptedResponse};
+
+    const LSBLK_JSON: &str = r#"{"blockdevices": [
+        {"name": "sda", "path": "/dev/sda", "size": 68719476736, "type": "disk",
+         "tran": "sata", "pttype": "gpt", "log-sec": 512, "phy-sec": 4096,
+         "rota": "0", "serial": "S1", "children": [
+            {"name": "sda1", "path": "/dev/sda1", "size": 536870912, "type": "part",
+             "fstype": "vfat", "start": 2048}
+         ]},
+        {"name": "sr0", "path": "/dev/sr0", "size": 1073741824, "type": "rom"}
+    ]}"#;
+
+    fn device(path: &str, serial: &str) -> DiskDevice {
+        DiskDevice {
+            identifier: DiskIdentifier {
+                path: path.into(),
+                size_bytes: 64 * 1024 * MIB,
+                label: None,
+                geometry: DiskGeometry::default(),
+                serial: Some(serial.into()),
+                wwn: None,
+                by_id: None,
+            },
+            transport: None,
+            read_only: false,
+            removable: false,
+            partition_table: None,
+            filesystem: None,
+            mountpoints: Vec::new(),
+            partitions: Vec::new(),
+            ineligible: None,
+            existing_systems: Vec::new(),
+            free_regions: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn probe_reads_disks_from_lsblk() {
+        let executor = ScriptedExecutor::new().on(
+            CommandMatcher::program("lsblk").with_args_containing(["--json", "--bytes"]),
+            ScriptedResponse::stdout(LSBLK_JSON),
+        );
+
+        let inventory = probe_inventory_with(&executor).unwrap();
+
+        assert_eq!(executor.call_count("lsblk"), 1);
+        assert_eq!(inventory.disks.len(), 1);
+        let disk = &inventory.disks[0];
+        assert_eq!(disk.identifier.path, "/dev/sda");
+        assert_eq!(disk.identifier.serial.as_deref(), Some("S1"));
+        assert_eq!(disk.identifier.geometry.physical_sector_bytes, 4096);
+        assert_eq!(disk.partitions.len(), 1);
+        assert_eq!(disk.partitions[0].start_bytes, Some(2048 * 512));
+    }
+
+    #[test]
+    fn failing_lsblk_is_an_error() {
+        let executor = ScriptedExecutor::new().on(
+            CommandMatcher::program("lsblk"),
+            ScriptedResponse::exit(32).with_stderr("lsblk: failed to access sysfs"),
+        );
+
+        assert!(probe_inventory_with(&executor).is_err());
+    }
+
+    #[test]
+    fn renamed_disk_is_found_by_serial() {
+        let expected = device("/dev/sda", "S1").identifier;
+        let inventory = DiskInventory {
+            disks: vec![device("/dev/sda", "S2"), device("/dev/sdb", "S1")],
+        };
+
+        let current = resolve_identity(&expected, &inventory).unwrap();
+        assert_eq!(current.path, "/dev/sdb");
+    }
+
+    #[test]
+    fn shared_serial_is_ambiguous() {
+        let expected = device("/dev/sda", "S1").identifier;
+        let inventory = DiskInventory {
+            disks: vec![device("/dev/sdb", "S1"), device("/dev/sdc", "S1")],
+        };
+
+        let err = resolve_identity(&expected, &inventory).unwrap_err();
+        assert!(err.to_string().contains("more than one disk"));
+    }
+
+    #[test]
+    fn missing_disk_is_reported() {
+        let expected = device("/dev/sda", "S1").identifier;
+        let inventory = DiskInventory {
+            disks: vec![device("/dev/sda", "S2")],
+        };
+
+        assert!(resolve_identity(&expected, &inventory).is_err());
+    }
+
+    const GIB: u64 = 1024 * MIB;
+
+    fn regions(json: &str) -> Vec<FreeSpaceRegion> {
+        let output: SfdiskOutput = serde_json::from_str(json).unwrap();
+        free_regions(&output.partitiontable)
+    }
+
+    #[test]
+    fn free_regions_cover_gaps_and_the_tail() {
+        // 64 GiB disk: ESP, a gap of 10.5 GiB, two partitions separated by
+        // 512 MiB, then free space up to the backup GPT.
+        let found = regions(
+            r#"{"partitiontable": {"label": "gpt", "firstlba": 2048,
+                "lastlba": 134217694, "sectorsize": 512, "partitions": [
+                {"node": "/dev/sda1", "start": 2048, "size": 1048576},
+                {"node": "/dev/sda3", "start": 66060288, "size": 20971520},
+                {"node": "/dev/sda2", "start": 23068672, "size": 41943040}
+            ]}}"#,
+        );
+
+        assert_eq!(
+            found,
+            vec![
+                FreeSpaceRegion {
+                    start_bytes: 513 * MIB,
+                    end_bytes: 11 * GIB,
+                    used_numbers: vec![1, 3, 2],
+                },
+                FreeSpaceRegion {
+                    start_bytes: 41 * GIB + 512 * MIB,
+                    // The last MiB holds the backup GPT.
+                    end_bytes: 64 * GIB - MIB,
+                    used_numbers: vec![1, 3, 2],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn free_regions_are_aligned_to_whole_mebibytes() {
+        let found = regions(
+            r#"{"partitiontable": {"label": "gpt", "firstlba": 34,
+                "lastlba": 8388574, "partitions": [
+                {"node": "/dev/sda1", "start": 2048, "size": 1048577}
+            ]}}"#,
+        );
+
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].start_bytes, 514 * MIB);
+        assert_eq!(found[0].end_bytes, 4 * GIB - MIB);
+    }
+
+    #[test]
+    fn small_gaps_are_not_offered() {
+        // 2 GiB disk whose only gap, 1022 MiB, is just below the minimum.
+        let found = regions(
+            r#"{"partitiontable": {"label": "gpt", "firstlba": 2048,
+                "lastlba": 4194270, "sectorsize": 512, "partitions": [
+                {"node": "/dev/sda1", "start": 2048, "size": 2097152}
+            ]}}"#,
+        );
+
+        assert!(found.is_empty());
+    }
+
+    #[test]
+    fn free_regions_need_a_gpt() {
+        let found = regions(
+            r#"{"partitiontable": {"label": "dos", "firstlba": 2048,
+                "lastlba": 134217727, "partitions": []}}"#,
+        );
+
+        assert!(found.is_empty());
+    }
+
+    #[test]
+    fn free_regions_use_the_logical_sector_size() {
+        let found = regions(
+            r#"{"partitiontable": {"label": "gpt", "firstlba": 256,
+                "lastlba": 16777210, "sectorsize": 4096, "partitions": [
+                {"node": "/dev/nvme0n1p1", "start": 256, "size": 131072}
+            ]}}"#,
+        );
+
+        assert_eq!(
+            found,
+            vec![FreeSpaceRegion {
+                start_bytes: 513 * MIB,
+                end_bytes: 64 * GIB - MIB,
+                used_numbers: vec![1],
+            }]
+        );
+    }
+
+    fn classify_lsblk(device: &str) -> Option<Ineligibility> {
+        let device: LsblkDevice = serde_json::from_str(device).unwrap();
+        classify(&disk_from_lsblk(device))
+    }
+
+    #[test]
+    fn disks_are_classified_by_the_first_matching_reason() {
+        let too_small = Some(Ineligibility::TooSmall {
+            minimum_bytes: MIN_TARGET_BYTES,
+        });
+        let cases = [
+            (
+                r#"{"name": "nvme0n1", "size": 68719476736, "type": "disk"}"#,
+                None,
+            ),
+            (
+                r#"{"name": "zram0", "size": 4294967296, "type": "disk"}"#,
+                Some(Ineligibility::Zram),
+            ),
+            (
+                r#"{"name": "sdb", "size": 8589934592, "type": "disk", "rm": true,
+                   "fstype": "iso9660"}"#,
+                Some(Ineligibility::LiveMedium),
+            ),
+            (
+                r#"{"name": "sdb", "size": 68719476736, "type": "disk", "children": [
+                    {"name": "sdb1", "size": 1073741824, "type": "part",
+                     "fstype": "iso9660"}]}"#,
+                Some(Ineligibility::LiveMedium),
+            ),
+            (
+                r#"{"name": "sdb", "size": 68719476736, "type": "disk", "children": [
+                    {"name": "sdb2", "size": 1073741824, "type": "part",
+                     "fstype": "vfat", "mountpoints": ["/run/archiso/bootmnt"]}]}"#,
+                Some(Ineligibility::LiveMedium),
+            ),
+            (
+                r#"{"name": "sdb", "size": 68719476736, "type": "disk", "children": [
+                    {"name": "sdb1", "size": 1073741824, "type": "part",
+                     "mountpoints": ["/run/live/medium/boot"]}]}"#,
+                Some(Ineligibility::LiveMedium),
+            ),
+            (
+                r#"{"name": "sr0", "size": 68719476736, "type": "disk", "ro": "1"}"#,
+                Some(Ineligibility::ReadOnly),
+            ),
+            (
+                r#"{"name": "mmcblk0", "size": 4294967296, "type": "disk", "ro": true}"#,
+                Some(Ineligibility::ReadOnly),
+            ),
+            (
+                r#"{"name": "sdc", "size": 8589934592, "type": "disk"}"#,
+                too_small.clone(),
+            ),
+            (
+                r#"{"name": "sdc", "size": 8589934592, "type": "disk", "children": [
+                    {"name": "sdc1", "size": 1073741824, "type": "part",
+                     "mountpoints": ["/home"]}]}"#,
+                too_small,
+            ),
+            (
+                r#"{"name": "sda", "size": 68719476736, "type": "disk", "children": [
+                    {"name": "sda2", "size": 1073741824, "type": "part",
+                     "mountpoints": [null, "/run/live/mediums"]}]}"#,
+                Some(Ineligibility::Mounted),
+            ),
+            (
+                r#"{"name": "sda", "size": 68719476736, "type": "disk",
+                   "mountpoints": ["/mnt"]}"#,
+                Some(Ineligibility::Mounted),
+            ),
+        ];
+
+        for (device, expected) in cases {
+            assert_eq!(classify_lsblk(device), expected, "{device}");
+        }
+    }
 }
@@ -0,0 +1,272 @@
+use anyhow::{anyhow, bail, Context, Result};
+
+use crate::state::{DiskMode, DiskPlan, FileSystem, PartitionFlag, PartitionSize, PartitionSpec};
+
+const KIB: u64 = 1024;
+const MIB: u64 = 1024 * KIB;
+const GIB: u64 = 1024 * MIB;
+const TIB: u64 = 1024 * GIB;
+
+/// Filesystems offered by the editor, by their [`FileSystem::label`].
+pub const EDITOR_FILESYSTEMS: &[&str] = &["ext4", "btrfs", "xfs", "fat32", "swap"];
+
+/// One change made in the manual partition editor.
+#[derive(Debug, Clone)]
+pub enum PartitionEdit {
+    /// Append a new 1 GiB ext4 partition.
+    Add,
+    Remove {
+        index: usize,
+    },
+    SetSize {
+        index: usize,
+        size: PartitionSize,
+    },
+    SetMountpoint {
+        index: usize,
+        mountpoint: Option<String>,
+    },
+    SetFilesystem {
+        index: usize,
+        filesystem: FileSystem,
+    },
+    SetFlags {
+        index: usize,
+        flags: Vec<PartitionFlag>,
+    },
+}
+
+/// Apply `edit` to `plan`. A whole-disk plan that is edited becomes
+/// [`DiskMode::Custom`]; free-space plans stay inside their region.
+pub fn apply_edit(plan: &mut DiskPlan, edit: PartitionEdit) -> Result<()> {
+    match edit {
+        PartitionEdit::Add => {
+            let id = unused_id(plan);
+            plan.partitions.push(PartitionSpec {
+                id,
+                mountpoint: None,
+                filesystem: FileSystem::Ext4,
+                size: PartitionSize::ExactBytes(GIB),
+                flags: Vec::new(),
+            });
+        }
+        PartitionEdit::Remove { index } => {
+            partition_mut(plan, index)?;
+            plan.partitions.remove(index);
+        }
+        PartitionEdit::SetSize { index, size } => partition_mut(plan, index)?.size = size,
+        PartitionEdit::SetMountpoint { index, mountpoint } => {
+            if let Some(mountpoint) = &mountpoint {
+                if !mountpoint.starts_with('/') {
+                    bail!("mount point {mountpoint:?} must be an absolute path");
+                }
+            }
+            partition_mut(plan, index)?.mountpoint = mountpoint;
+        }
+        PartitionEdit::SetFilesystem { index, filesystem } => {
+            partition_mut(plan, index)?.filesystem = filesystem;
+        }
+        PartitionEdit::SetFlags { index, flags } => partition_mut(plan, index)?.flags = flags,
+    }
+
+    if plan.mode == DiskMode::UseEntireDisk {
+        plan.mode = DiskMode::Custom;
+    }
+    Ok(())
+}
+
+/// Parse a size as typed in the editor: `512MiB`, `20 GiB`, `1024` (bytes),
+/// `25%`, or `rest` for the remaining space.
+pub fn parse_size(text: &str) -> Result<PartitionSize> {
+    let text = text.trim();
+    if text.is_empty()
+        || text.eq_ignore_ascii_case("rest")
+        || text.eq_ignore_ascii_case("remainder")
+    {
+        return Ok(PartitionSize::Remainder);
+    }
+
+    if let Some(percent) = text.strip_suffix('%') {
+        let percent: u8 = percent
+            .trim()
+            .parse()
+            .with_context(|| format!("invalid percentage {text:?}"))?;
+        if percent == 0 || percent > 100 {
+            bail!("percentage must be between 1 and 100");
+        }
+        return Ok(PartitionSize::Percentage(percent));
+    }
+
+    let split = text
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(text.len());
+    let (number, unit) = text.split_at(split);
+    let number: f64 = number
+        .parse()
+        .with_context(|| format!("invalid size {text:?}"))?;
+    let multiplier = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kib" => KIB,
+        "m" | "mib" => MIB,
+        "g" | "gib" => GIB,
+        "t" | "tib" => TIB,
+        other => bail!("unknown size unit {other:?}"),
+    };
+
+    let bytes = (number * multiplier as f64).round();
+    // `as u64` would quietly saturate anything larger.
+    if bytes >= u64::MAX as f64 {
+        bail!("size {text:?} is too large");
+    }
+    if bytes == 0.0 {
+        bail!("size must be larger than zero");
+    }
+    Ok(PartitionSize::ExactBytes(bytes as u64))
+}
+
+/// Inverse of [`parse_size`], using the largest unit that divides evenly.
+pub fn format_size(size: &PartitionSize) -> String {
+    match size {
+        PartitionSize::ExactBytes(bytes) => {
+            let (value, unit) = [(TIB, "TiB"), (GIB, "GiB"), (MIB, "MiB"), (KIB, "KiB")]
+                .into_iter()
+                .find(|(unit, _)| bytes % unit == 0)
+                .map(|(unit, name)| (bytes / unit, name))
+                .unwrap_or((*bytes, "B"));
+            format!("{value}{unit}")
+        }
+        PartitionSize::Percentage(percent) => format!("{percent}%"),
+        PartitionSize::Remainder => "rest".into(),
+    }
+}
+
+pub fn parse_filesystem(label: &str) -> Result<FileSystem> {
+    Ok(match label.trim() {
+        "ext4" => FileSystem::Ext4,
+        "btrfs" => FileSystem::Btrfs,
+        "xfs" => FileSystem::Xfs,
+        "fat32" | "vfat" => FileSystem::Fat32,
+        "swap" => FileSystem::Swap,
+        "" => bail!("filesystem cannot be empty"),
+        other => FileSystem::Other(other.to_string()),
+    })
+}
+
+/// Parse a comma separated flag list such as `esp, boot`.
+pub fn parse_flags(text: &str) -> Vec<PartitionFlag> {
+    text.split(',')
+        .map(str::trim)
+        .filter(|flag| !flag.is_empty())
+        .map(|flag| match flag.to_ascii_lowercase().as_str() {
+            "boot" => PartitionFlag::Boot,
+            "esp" => PartitionFlag::Esp,
+            "swap" => PartitionFlag::Swap,
+            "lvm" => PartitionFlag::Lvm,
+            _ => PartitionFlag::Custom(flag.to_string()),
+        })
+        .collect()
+}
+
+pub fn format_flags(flags: &[PartitionFlag]) -> String {
+    flags
+        .iter()
+        .map(|flag| match flag {
+            PartitionFlag::Boot => "boot",
+            PartitionFlag::Esp => "esp",
+            PartitionFlag::Swap => "swap",
+            PartitionFlag::Lvm => "lvm",
+            PartitionFlag::Custom(name) => name.as_str(),
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn partition_mut(plan: &mut DiskPlan, index: usize) -> Result<&mut PartitionSpec> {
+    let count = plan.partitions.len();
+    plan.partitions
+        .get_mut(index)
+        .ok_or_else(|| anyhow!("partition {index} out of range ({count} partitions)"))
+}
+
+fn unused_id(plan: &DiskPlan) -> String {
+    (plan.partitions.len() + 1..)
+        .map(|n| format!("part{n}"))
+        .find(|id| plan.partitions.iter().all(|spec| &spec.id != id))
+        .unwrap_or_else(|| "part".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes_parse_with_binary_units() {
+        let cases = [
+            ("1024", 1024),
+            ("1024B", 1024),
+            ("4k", 4 * KIB),
+            ("512MiB", 512 * MIB),
+            ("512 mib", 512 * MIB),
+            ("20 GiB", 20 * GIB),
+            ("20G", 20 * GIB),
+            ("2TiB", 2 * TIB),
+            ("1.5GiB", 1536 * MIB),
+            (".5 G", 512 * MIB),
+            ("  8 GiB  ", 8 * GIB),
+        ];
+
+        for (text, bytes) in cases {
+            assert_eq!(
+                parse_size(text).unwrap(),
+                PartitionSize::ExactBytes(bytes),
+                "{text:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn percentages_and_the_remainder_parse() {
+        assert_eq!(parse_size("25%").unwrap(), PartitionSize::Percentage(25));
+        assert_eq!(
+            parse_size(" 100 % ").unwrap(),
+            PartitionSize::Percentage(100)
+        );
+        assert_eq!(parse_size("rest").unwrap(), PartitionSize::Remainder);
+        assert_eq!(parse_size("Remainder").unwrap(), PartitionSize::Remainder);
+        assert_eq!(parse_size("   ").unwrap(), PartitionSize::Remainder);
+    }
+
+    #[test]
+    fn invalid_sizes_are_rejected() {
+        for text in [
+            "0",
+            "0 GiB",
+            "0.0001",
+            "-5GiB",
+            "-1",
+            "0%",
+            "101%",
+            "-5%",
+            "1.2.3G",
+            "5 PiB",
+            "GiB",
+            "20000000 TiB",
+            "99999999999999999999",
+        ] {
+            assert!(parse_size(text).is_err(), "{text:?} should be rejected");
+        }
+    }
+
+    #[test]
+    fn formatted_sizes_parse_back() {
+        for size in [
+            PartitionSize::ExactBytes(512 * MIB),
+            PartitionSize::ExactBytes(3 * TIB),
+            PartitionSize::ExactBytes(1000),
+            PartitionSize::Percentage(40),
+            PartitionSize::Remainder,
+        ] {
+            assert_eq!(parse_size(&format_size(&size)).unwrap(), size);
+        }
+    }
+}
@@ -0,0 +1,75 @@
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::os::unix::fs::{symlink as unix_symlink, OpenOptionsExt, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+
+/// Resolve an absolute path of the installed system to its location under
+/// `root` on the live system.
+pub fn target_path(root: &str, path: &str) -> Result<PathBuf> {
+    let relative = Path::new(path);
+    if !relative.is_absolute() {
+        bail!("target path {path} must be absolute");
+    }
+
+    let mut resolved = PathBuf::from(root);
+    for component in relative.components() {
+        match component {
+            Component::RootDir => {}
+            Component::Normal(part) => resolved.push(part),
+            Component::CurDir => {}
+            Component::ParentDir | Component::Prefix(_) => {
+                bail!("target path {path} must not contain parent components")
+            }
+        }
+    }
+
+    Ok(resolved)
+}
+
+pub fn write_file(root: &str, path: &str, contents: &str, mode: u32) -> Result<()> {
+    let destination = target_path(root, path)?;
+    create_parent(&destination)?;
+
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(mode)
+        .open(&destination)
+        .with_context(|| format!("failed to open {}", destination.display()))?;
+    file.write_all(contents.as_bytes())
+        .with_context(|| format!("failed to write {}", destination.display()))?;
+
+    // `mode` only applies when the file is created; enforce it for
+    // pre-existing files as well.
+    fs::set_permissions(&destination, fs::Permissions::from_mode(mode))
+        .with_context(|| format!("failed to set permissions on {}", destination.display()))?;
+
+    Ok(())
+}
+
+pub fn symlink(root: &str, target: &str, link: &str) -> Result<()> {
+    let destination = target_path(root, link)?;
+    create_parent(&destination)?;
+
+    match fs::remove_file(&destination) {
+        Ok(()) => {}
+        Err(err) if err.kind() == ErrorKind::NotFound => {}
+        Err(err) => {
+            return Err(err).with_context(|| format!("failed to replace {}", destination.display()))
+        }
+    }
+
+    unix_symlink(target, &destination)
+        .with_context(|| format!("failed to link {} -> {}", destination.display(), target))
+}
+
+fn create_parent(path: &Path) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("failed to create {}", parent.display()))?;
+    }
+    Ok(())
+}
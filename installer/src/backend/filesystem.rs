@@ -1,7 +1,7 @@
 use anyhow::{bail, Result};
 
 use crate::backend::command::CommandSpec;
-use crate::state::{FileSystem, PartitionSpec};
+use crate::state::{DiscardPolicy, FileSystem, PartitionSpec};
 
 pub fn mkfs_command(device_path: &str, spec: &PartitionSpec) -> Result<Option<CommandSpec>> {
     match &spec.filesystem {
@@ -35,6 +35,7 @@ pub fn mount_command(
     spec: &PartitionSpec,
     device_path: &str,
     root: &str,
+    discard: DiscardPolicy,
 ) -> Result<Option<CommandSpec>> {
     let Some(mountpoint) = spec.mountpoint.as_ref() else {
         return Ok(None);
@@ -50,17 +51,35 @@ pub fn mount_command(
         mountpoint.trim_start_matches('/')
     );
 
+    let mut args = Vec::new();
+    if discard == DiscardPolicy::Continuous {
+        if let Some(option) = discard_mount_option(&spec.filesystem) {
+            args.push("-o".into());
+            args.push(option.into());
+        }
+    }
+    args.push(device_path.into());
+    args.push(full_target);
+
     let command = match spec.filesystem {
         FileSystem::Swap => None,
-        _ => Some(CommandSpec::new(
-            "mount",
-            vec![device_path.into(), full_target],
-        )),
+        _ => Some(CommandSpec::new("mount", args)),
     };
 
     Ok(command)
 }
 
+/// Mount option enabling online discard, where the filesystem has one.
+fn discard_mount_option(fs: &FileSystem) -> Option<&'static str> {
+    match fs {
+        FileSystem::Ext4 | FileSystem::Xfs | FileSystem::Fat32 => Some("discard"),
+        // Synchronous discard is slow on btrfs; the async variant is the
+        // recommended form.
+        FileSystem::Btrfs => Some("discard=async"),
+        FileSystem::Swap | FileSystem::Other(_) => None,
+    }
+}
+
 pub fn activate_swap_command(device_path: &str) -> CommandSpec {
     CommandSpec::new("swapon", vec![device_path.into()])
 }
@@ -0,0 +1,186 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use tracing::{debug, info, warn};
+
+use crate::backend::command::{CancellationToken, CommandExecutor, CommandSpec, OutputStream};
+use crate::backend::{disk, Backend};
+use crate::state::DiskInventory;
+
+/// How often the watcher wakes up to check for shutdown.
+const WAKE_INTERVAL: Duration = Duration::from_secs(1);
+/// Quiet period after a udev event before rescanning; plugging in a disk
+/// produces a burst of events for the disk and each partition.
+const DEBOUNCE: Duration = Duration::from_millis(500);
+/// Rescan interval used when udev events are not available.
+pub const RESCAN_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Watches for block devices being added or removed and reports the new
+/// inventory. Stops when dropped.
+pub struct DiskWatcher {
+    stop: CancellationToken,
+    paused: Arc<AtomicBool>,
+}
+
+impl DiskWatcher {
+    /// Start watching. `on_change` runs on the watcher thread whenever the
+    /// set of disks (or what is on them) changes.
+    pub fn spawn<F>(backend: Backend, on_change: F) -> Self
+    where
+        F: Fn(DiskInventory) + Send + 'static,
+    {
+        let stop = CancellationToken::new();
+        let paused = Arc::new(AtomicBool::new(false));
+        let (events_tx, events) = mpsc::channel();
+
+        let executor = backend.executor();
+        let monitor_stop = stop.clone();
+        thread::spawn(move || {
+            monitor_udev(executor.as_ref(), &monitor_stop, &events_tx);
+        });
+
+        let worker_stop = stop.clone();
+        let worker_paused = Arc::clone(&paused);
+        thread::spawn(move || {
+            let mut worker = Worker {
+                last: disk::probe_inventory_with(backend.executor().as_ref()).ok(),
+                backend,
+                on_change,
+            };
+            worker.run(&events, &worker_stop, &worker_paused);
+        });
+
+        Self { stop, paused }
+    }
+
+    /// Hold back rescans, e.g. while the installer is writing to the disk.
+    /// Changes seen meanwhile are picked up on [`DiskWatcher::resume`].
+    pub fn pause(&self) {
+        self.paused.store(true, Ordering::SeqCst);
+    }
+
+    pub fn resume(&self) {
+        self.paused.store(false, Ordering::SeqCst);
+    }
+
+    pub fn stop(&self) {
+        self.stop.cancel();
+    }
+}
+
+impl Drop for DiskWatcher {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+struct Worker<F> {
+    backend: Backend,
+    on_change: F,
+    /// Last lsblk view, used to skip the full probe when nothing changed.
+    last: Option<DiskInventory>,
+}
+
+impl<F> Worker<F>
+where
+    F: Fn(DiskInventory),
+{
+    fn run(&mut self, events: &mpsc::Receiver<()>, stop: &CancellationToken, paused: &AtomicBool) {
+        let mut udev_available = true;
+        let mut pending = false;
+        let mut last_scan = Instant::now();
+
+        while !stop.is_cancelled() {
+            match events.recv_timeout(WAKE_INTERVAL) {
+                Ok(()) => {
+                    while events.recv_timeout(DEBOUNCE).is_ok() {}
+                    pending = true;
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => {
+                    if udev_available {
+                        warn!(
+                            "udev monitor unavailable, rescanning disks every {:?}",
+                            RESCAN_INTERVAL
+                        );
+                        udev_available = false;
+                    }
+                    thread::sleep(WAKE_INTERVAL);
+                }
+            }
+
+            if !udev_available && last_scan.elapsed() >= RESCAN_INTERVAL {
+                pending = true;
+            }
+            if !pending || paused.load(Ordering::SeqCst) || stop.is_cancelled() {
+                continue;
+            }
+
+            pending = false;
+            last_scan = Instant::now();
+            self.rescan();
+        }
+    }
+
+    fn rescan(&mut self) {
+        let snapshot = match disk::probe_inventory_with(self.backend.executor().as_ref()) {
+            Ok(snapshot) => snapshot,
+            Err(err) => {
+                warn!("failed to rescan block devices: {:#}", err);
+                return;
+            }
+        };
+        if self.last.as_ref() == Some(&snapshot) {
+            debug!("block devices unchanged");
+            return;
+        }
+        self.last = Some(snapshot);
+
+        match self.backend.probe_inventory() {
+            Ok(inventory) => {
+                info!(count = inventory.disks.len(), "block devices changed");
+                (self.on_change)(inventory);
+            }
+            Err(err) => warn!("failed to probe block devices: {:#}", err),
+        }
+    }
+}
+
+/// Forward block add/remove/change events from `udevadm monitor` until it
+/// exits or `stop` is cancelled. Returning drops `events`, which switches
+/// the worker to periodic rescans.
+fn monitor_udev(
+    executor: &dyn CommandExecutor,
+    stop: &CancellationToken,
+    events: &mpsc::Sender<()>,
+) {
+    let spec = CommandSpec::new(
+        "udevadm",
+        vec![
+            "monitor".into(),
+            "--udev".into(),
+            "--subsystem-match=block".into(),
+        ],
+    );
+
+    let result = executor.run_streaming(&spec, stop, &mut |stream, line| {
+        if stream == OutputStream::Stdout && is_block_event(line) {
+            let _ = events.send(());
+        }
+    });
+    if let Err(err) = result {
+        if !stop.is_cancelled() {
+            warn!("udev monitor stopped: {:#}", err);
+        }
+    }
+}
+
+/// Event lines look like
+/// `UDEV  [1234.567890] add      /devices/.../block/sdb (block)`.
+fn is_block_event(line: &str) -> bool {
+    let mut fields = line.split_whitespace();
+    fields.next() == Some("UDEV") && matches!(fields.nth(1), Some("add" | "remove" | "change"))
+}
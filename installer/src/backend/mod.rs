@@ -1,108 +1,694 @@
 pub mod boot;
+pub mod chroot;
 pub mod cleanup;
 pub mod command;
+pub mod concurrent;
 pub mod config;
 pub mod disk;
+pub mod editor;
+pub mod files;
 pub mod filesystem;
+pub mod hotplug;
+pub mod os_probe;
 pub mod packages;
 pub mod partition;
+pub mod platform;
+pub mod preflight;
+pub mod resize;
+#[cfg(test)]
+pub mod scripted;
+pub mod sfdisk;
 pub mod tasks;
+pub mod transcript;
 
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 
-use anyhow::{Context, Result};
-use parking_lot::RwLock;
-use tracing::{error, info};
+use anyhow::{bail, Context, Result};
+use parking_lot::{Mutex, RwLock};
+use tracing::{error, info, warn};
 
-use crate::state::{DiskIdentifier, InstallerState};
-use command::{CommandExecutor, SystemCommandExecutor};
-use tasks::{build_plan, InstallPlan};
+use crate::state::{
+    DiskGeometry, DiskIdentifier, DiskInventory, DiskPlan, FreeSpaceRegion, InstallerState,
+    PartitionResize, PartitionSize, Platform,
+};
+use chroot::{ChrootExecutor, ChrootMode};
+use command::{
+    CancellationToken, CommandError, CommandExecutor, CommandOutput, CommandSpec, OutputStream,
+    SystemCommandExecutor,
+};
+use concurrent::{AsyncCommandExecutor, TokioCommandExecutor};
+use partition::PlanError;
+use preflight::PreflightReport;
+use tasks::{build_plan, InstallPlan, PlanAction};
+use transcript::{Transcript, TranscriptRecord, TRANSCRIPT_DIR};
+
+/// Size of the stand-in disk used to plan a preflight check before the user
+/// has picked a target.
+const PREFLIGHT_DISK_BYTES: u64 = 64 * 1024 * 1024 * 1024;
 
 pub struct Backend {
     state: Arc<RwLock<InstallerState>>,
     executor: Arc<dyn CommandExecutor + Send + Sync>,
+    async_executor: Arc<dyn AsyncCommandExecutor>,
+    chroot_mode: ChrootMode,
+    transcript_dir: PathBuf,
+    probe_cache: Arc<Mutex<os_probe::ProbeCache>>,
+}
+
+/// Everything an action needs while a plan is being executed.
+struct ExecutionContext<'a> {
+    plan: &'a InstallPlan,
+    chroot: ChrootExecutor,
+    cancel: &'a CancellationToken,
+    transcript: Option<&'a Transcript>,
 }
 
 impl Backend {
     pub fn new(state: Arc<RwLock<InstallerState>>) -> Self {
         Self {
             state,
-            executor: Arc::new(SystemCommandExecutor::default()),
+            executor: Arc::new(SystemCommandExecutor),
+            async_executor: Arc::new(TokioCommandExecutor),
+            chroot_mode: ChrootMode::default(),
+            transcript_dir: PathBuf::from(TRANSCRIPT_DIR),
+            probe_cache: Arc::default(),
         }
     }
 
+    #[cfg(test)]
     pub fn with_executor(
         state: Arc<RwLock<InstallerState>>,
         executor: Arc<dyn CommandExecutor + Send + Sync>,
     ) -> Self {
-        Self { state, executor }
+        Self {
+            state,
+            async_executor: Arc::new(scripted::BlockingExecutor::new(Arc::clone(&executor))),
+            executor,
+            chroot_mode: ChrootMode::default(),
+            transcript_dir: PathBuf::from(TRANSCRIPT_DIR),
+            probe_cache: Arc::default(),
+        }
+    }
+
+    /// Select how target-side commands enter the installed system.
+    pub fn with_chroot_mode(mut self, mode: ChrootMode) -> Self {
+        self.chroot_mode = mode;
+        self
+    }
+
+    /// Write the command transcript somewhere other than [`TRANSCRIPT_DIR`].
+    #[cfg(test)]
+    pub fn with_transcript_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.transcript_dir = dir.into();
+        self
+    }
+
+    fn chroot_executor(&self, plan: &InstallPlan) -> ChrootExecutor {
+        ChrootExecutor::new(
+            Arc::clone(&self.executor),
+            plan.target_root(),
+            self.chroot_mode,
+        )
     }
 
+    /// Probe the attached disks, their unallocated space and the operating
+    /// systems installed on them. Filesystems searched for an installed
+    /// system on an earlier probe are not mounted again.
+    pub fn probe_inventory(&self) -> Result<DiskInventory> {
+        let mut inventory = disk::probe_inventory_with(self.executor.as_ref())?;
+        disk::attach_free_space(&mut inventory, self.executor.as_ref());
+        os_probe::detect_existing_systems(
+            &mut inventory,
+            self.executor.as_ref(),
+            Path::new(os_probe::PROBE_MOUNT_DIR),
+            &mut self.probe_cache.lock(),
+        );
+        Ok(inventory)
+    }
+
+    #[allow(dead_code)]
     pub fn list_disks(&self) -> Result<Vec<DiskIdentifier>> {
         disk::probe_block_devices_with(self.executor.as_ref())
     }
 
-    pub fn begin_installation(&self) -> Result<InstallPlan> {
-        let state_snapshot = self.state.read().clone();
+    /// Rescan the disks whenever block devices come or go.
+    pub fn watch_disks<F>(&self, on_change: F) -> hotplug::DiskWatcher
+    where
+        F: Fn(DiskInventory) + Send + 'static,
+    {
+        hotplug::DiskWatcher::spawn(self.clone(), on_change)
+    }
+
+    pub fn executor(&self) -> Arc<dyn CommandExecutor + Send + Sync> {
+        Arc::clone(&self.executor)
+    }
+
+    /// Apply a manual layout change to the target plan, starting from the
+    /// default layout if the selected disk has none yet. Returns the problems
+    /// left in the edited layout.
+    pub fn edit_partitions(&self, edit: editor::PartitionEdit) -> Result<Vec<PlanError>> {
+        let mut state = self.state.write();
+        let plan = target_or_default(&mut state)?;
+        editor::apply_edit(plan, edit)?;
+        Ok(partition::validate_target(&state))
+    }
+
+    /// Resize the partition mounted at `/`. Returns the problems left in the
+    /// layout, like [`Backend::edit_partitions`].
+    pub fn set_root_size(&self, size: PartitionSize) -> Result<Vec<PlanError>> {
+        let mut state = self.state.write();
+        let plan = target_or_default(&mut state)?;
+        let index = plan
+            .partitions
+            .iter()
+            .position(|spec| {
+                spec.mountpoint
+                    .as_deref()
+                    .map(partition::normalize_mountpoint)
+                    == Some("/")
+            })
+            .context("the layout has no root partition")?;
+        editor::apply_edit(plan, editor::PartitionEdit::SetSize { index, size })?;
+        Ok(partition::validate_target(&state))
+    }
+
+    /// Make room by shrinking the partition at `path` on the selected disk
+    /// to `new_bytes`, and lay out the default partitions in the space that
+    /// frees up. Replaces any layout edited so far.
+    pub fn shrink_partition(&self, path: &str, new_bytes: u64) -> Result<PartitionResize> {
+        let mut state = self.state.write();
+        let disk = state
+            .selected_disk
+            .clone()
+            .context("no target disk selected")?;
+        let device = state
+            .disk_inventory
+            .find(&disk.path)
+            .with_context(|| format!("{} is no longer attached", disk.path))?;
+        let (shrink, region) = resize::plan_shrink(device, path, new_bytes)?;
+
+        let mut plan = partition::plan_for_free_space(&disk, &region);
+        plan.resizes = vec![shrink.clone()];
+        state.target = Some(plan);
+        Ok(shrink)
+    }
+
+    /// Lay out the default partitions in the selected disk's free region
+    /// `index`, leaving the existing partitions alone. Replaces any layout
+    /// edited so far.
+    pub fn use_free_space(&self, index: usize) -> Result<FreeSpaceRegion> {
+        let mut state = self.state.write();
+        let disk = state
+            .selected_disk
+            .clone()
+            .context("no target disk selected")?;
+        let region = state
+            .disk_inventory
+            .find(&disk.path)
+            .with_context(|| format!("{} is no longer attached", disk.path))?
+            .free_regions
+            .get(index)
+            .cloned()
+            .context("choose a free space region")?;
+
+        state.target = Some(partition::plan_for_free_space(&disk, &region));
+        Ok(region)
+    }
+
+    /// Problems with the current target plan; empty when there is none.
+    pub fn validate_target(&self) -> Vec<PlanError> {
+        partition::validate_target(&self.state.read())
+    }
+
+    /// Let sfdisk check the partition table it would write for the current
+    /// target, without touching the disk.
+    pub fn dry_run_partitioning(&self) -> Result<CommandOutput> {
+        let plan = {
+            let state = self.state.read();
+            match (&state.target, &state.selected_disk) {
+                (Some(plan), _) => plan.clone(),
+                (None, Some(disk)) => partition::default_plan_for_disk(disk),
+                (None, None) => bail!("no target disk selected"),
+            }
+        };
+
+        let spec = sfdisk::dry_run_command(&plan)?;
+        let output = self.executor.run(&spec)?;
+        if !output.success() {
+            bail!(
+                "sfdisk rejected the partition layout for {}: {}",
+                plan.target.path,
+                output.stderr.trim()
+            );
+        }
+        Ok(output)
+    }
+
+    /// Work out which kind of machine this is and remember it in the state.
+    pub fn detect_platform(&self) -> Platform {
+        let platform = platform::detect(self.executor.as_ref());
+        self.state.write().platform = platform.clone();
+        platform
+    }
+
+    /// Check that the tools needed to probe disks and run the install are
+    /// present. Before a disk is chosen the default layout is assumed.
+    pub fn preflight(&self) -> Result<PreflightReport> {
+        let mut state_snapshot = self.state.read().clone();
+        if state_snapshot.target.is_none() && state_snapshot.selected_disk.is_none() {
+            state_snapshot.selected_disk = Some(DiskIdentifier {
+                path: "/dev/preflight".into(),
+                size_bytes: PREFLIGHT_DISK_BYTES,
+                label: None,
+                geometry: DiskGeometry::default(),
+                serial: None,
+                wwn: None,
+                by_id: None,
+            });
+        }
+
         let plan = build_plan(&state_snapshot)?;
+        let chroot = self.chroot_executor(&plan);
+        let mut programs = preflight::required_programs(plan.steps(), &chroot);
+        programs.push(disk::probe_command().program);
+        programs.push(disk::free_space_command("/dev/preflight").program);
+        programs.sort();
+        programs.dedup();
+
+        Ok(preflight::check_tools(&programs, self.executor.as_ref()))
+    }
+
+    /// Build the install plan for the current selections without scheduling
+    /// it, e.g. to preview it while the layout is being edited.
+    pub fn prepare_plan(&self) -> Result<InstallPlan> {
+        let state_snapshot = self.state.read().clone();
+        let problems = partition::validate_target(&state_snapshot);
+        if !problems.is_empty() {
+            let problems: Vec<String> = problems.iter().map(ToString::to_string).collect();
+            bail!("partition layout is not usable: {}", problems.join("; "));
+        }
+        build_plan(&state_snapshot)
+    }
+
+    pub fn begin_installation(&self) -> Result<InstallPlan> {
+        let plan = self.prepare_plan()?;
 
         for step in plan.steps() {
-            info!(stage = ?step.stage, summary = step.summary, command_count = step.commands.len(), "scheduled install step");
+            info!(stage = ?step.stage, summary = step.summary, action_count = step.actions.len(), "scheduled install step");
         }
 
         Ok(plan)
     }
 
-    pub fn execute_plan_stream<F>(&self, plan: InstallPlan, mut on_log: F) -> Result<bool>
+    pub fn execute_plan_stream<F>(
+        &self,
+        plan: InstallPlan,
+        cancel: &CancellationToken,
+        mut on_log: F,
+    ) -> Result<InstallOutcome>
     where
         F: FnMut(String),
     {
-        let mut success = true;
+        let mut outcome = InstallOutcome::Completed;
+        let transcript = match Transcript::open(&self.transcript_dir) {
+            Ok(transcript) => {
+                info!(path = %transcript.path().display(), "recording command transcript");
+                Some(transcript)
+            }
+            Err(err) => {
+                warn!("command transcript disabled: {:#}", err);
+                on_log(format!("warning: command transcript disabled: {err:#}"));
+                None
+            }
+        };
+        let mut plan = plan;
 
-        for step in plan.steps() {
+        // Steps are walked by index because verifying the target may swap in
+        // a plan rebuilt for the disk's new kernel name.
+        'steps: for step_index in 0..plan.steps().len() {
+            let step = &plan.steps()[step_index];
             on_log(format!("== {:?} ==\n{}", step.stage, step.summary));
 
-            for command in &step.commands {
-                on_log(format!("$ {} {}", command.program, command.args.join(" ")));
-
-                match self
-                    .executor
-                    .run(command)
-                    .with_context(|| format!("failed to run {}", command.program))
-                {
-                    Ok(output) => {
-                        for line in output.stdout.lines().filter(|l| !l.trim().is_empty()) {
-                            on_log(line.to_string());
-                        }
-                        for line in output.stderr.lines().filter(|l| !l.trim().is_empty()) {
-                            on_log(format!("stderr: {line}"));
-                        }
+            for action_index in 0..step.actions.len() {
+                if cancel.is_cancelled() {
+                    outcome = InstallOutcome::Cancelled;
+                    break 'steps;
+                }
+
+                let action = plan.steps()[step_index].actions[action_index].clone();
+                on_log(action.to_string());
 
+                let result = match &action {
+                    PlanAction::VerifyTarget(expected) => self
+                        .verify_target(&mut plan, expected, &mut on_log)
+                        .map(|()| None),
+                    _ => {
+                        let ctx = ExecutionContext {
+                            plan: &plan,
+                            chroot: self.chroot_executor(&plan),
+                            cancel,
+                            transcript: transcript.as_ref(),
+                        };
+                        self.apply_action(&ctx, &action, &mut on_log)
+                    }
+                };
+                match result {
+                    Ok(None) => {}
+                    Ok(Some(output)) => {
                         if !output.success() {
-                            success = false;
+                            outcome = InstallOutcome::Failed;
                             on_log(format!(
                                 "command exited with status {:?}",
                                 output.status.code()
                             ));
-                            break;
+                            break 'steps;
                         }
                     }
                     Err(err) => {
-                        success = false;
-                        error!("command failed: {:#}", err);
+                        outcome = match err.downcast_ref::<CommandError>() {
+                            Some(CommandError::Cancelled { .. }) => InstallOutcome::Cancelled,
+                            Some(CommandError::TimedOut { program, timeout }) => {
+                                InstallOutcome::TimedOut {
+                                    program: program.clone(),
+                                    timeout: *timeout,
+                                }
+                            }
+                            None => InstallOutcome::Failed,
+                        };
+                        error!("install action failed: {:#}", err);
                         on_log(format!("error: {err:#}"));
-                        break;
+                        break 'steps;
                     }
                 }
             }
+        }
+
+        Ok(outcome)
+    }
+
+    /// Carry out one plan action. Command actions return their output so the
+    /// caller can inspect the exit status; file actions return `None`.
+    fn apply_action<F>(
+        &self,
+        ctx: &ExecutionContext<'_>,
+        action: &PlanAction,
+        on_log: &mut F,
+    ) -> Result<Option<CommandOutput>>
+    where
+        F: FnMut(String),
+    {
+        let root = ctx.plan.target_root();
+        let (executor, command): (&dyn CommandExecutor, &CommandSpec) = match action {
+            PlanAction::Run(spec) => (self.executor.as_ref(), spec),
+            PlanAction::RunInChroot(spec) => (&ctx.chroot, spec),
+            PlanAction::RunConcurrently(specs) => {
+                return self.run_concurrently(ctx, specs, on_log);
+            }
+            PlanAction::WriteFile {
+                path,
+                contents,
+                mode,
+            } => {
+                files::write_file(root, path, contents, *mode)?;
+                return Ok(None);
+            }
+            PlanAction::Symlink { target, link } => {
+                files::symlink(root, target, link)?;
+                return Ok(None);
+            }
+            PlanAction::VerifyTarget(_) => {
+                bail!("target verification must go through Backend::verify_target")
+            }
+            PlanAction::CheckTools => {
+                let programs = preflight::required_programs(ctx.plan.steps(), &ctx.chroot);
+                let report = preflight::check_tools(&programs, self.executor.as_ref());
+                for tool in &report.tools {
+                    if let Some(version) = &tool.version {
+                        on_log(format!("{}: {version}", tool.program));
+                    }
+                }
+                if !report.is_ready() {
+                    bail!(report.describe());
+                }
+                return Ok(None);
+            }
+            PlanAction::SaveTranscript => {
+                match ctx.transcript {
+                    Some(transcript) => {
+                        let destination = transcript.copy_into(root)?;
+                        on_log(format!("transcript saved to {}", destination.display()));
+                    }
+                    None => on_log("no transcript to save".to_string()),
+                }
+                return Ok(None);
+            }
+        };
+
+        let mut on_line = |stream: OutputStream, line: &str| {
+            if line.trim().is_empty() {
+                return;
+            }
+            match stream {
+                OutputStream::Stdout => on_log(line.to_string()),
+                OutputStream::Stderr => on_log(format!("stderr: {line}")),
+            }
+        };
+        let result = self.run_with_retries(ctx, executor, command, &mut on_line);
+
+        let succeeded = matches!(&result, Ok(Some(output)) if output.success());
+        if !succeeded {
+            self.run_cleanup(ctx, executor, command, on_log);
+        }
+        result
+    }
+
+    /// Run `command`, running it again as its [`RetryPolicy`] allows.
+    ///
+    /// [`RetryPolicy`]: command::RetryPolicy
+    fn run_with_retries(
+        &self,
+        ctx: &ExecutionContext<'_>,
+        executor: &dyn CommandExecutor,
+        command: &CommandSpec,
+        on_line: &mut dyn FnMut(OutputStream, &str),
+    ) -> Result<Option<CommandOutput>> {
+        let mut attempt = 1;
+        loop {
+            let started_at = SystemTime::now();
+            let clock = Instant::now();
+            let result = executor.run_streaming(command, ctx.cancel, on_line);
+            if let Some(transcript) = ctx.transcript {
+                let record = TranscriptRecord::new(command, started_at, clock.elapsed(), &result);
+                if let Err(err) = transcript.record(&record) {
+                    warn!("failed to record transcript entry: {:#}", err);
+                }
+            }
+            let output = result.with_context(|| format!("failed to run {}", command.program))?;
+
+            let Some(policy) = command
+                .retry
+                .as_ref()
+                .filter(|policy| !output.success() && policy.should_retry(attempt, &output.status))
+            else {
+                return Ok(Some(output));
+            };
+
+            let delay = policy.delay_after(attempt);
+            warn!(
+                program = command.program,
+                attempt,
+                ?delay,
+                "retrying command"
+            );
+            on_line(
+                OutputStream::Stderr,
+                &format!(
+                    "attempt {attempt}/{} of {} exited with status {:?}; retrying in {delay:?}",
+                    policy.max_attempts,
+                    command.program,
+                    output.status.code()
+                ),
+            );
+
+            if !sleep_unless_cancelled(delay, ctx.cancel) {
+                return Err(CommandError::Cancelled {
+                    program: command.program.clone(),
+                }
+                .into());
+            }
+            attempt += 1;
+        }
+    }
+
+    /// Run the cleanup commands of a failed `command`, e.g. unmounting what
+    /// it was working on. They run even when the install was cancelled, and
+    /// their own failures are only logged.
+    fn run_cleanup<F>(
+        &self,
+        ctx: &ExecutionContext<'_>,
+        executor: &dyn CommandExecutor,
+        command: &CommandSpec,
+        on_log: &mut F,
+    ) where
+        F: FnMut(String),
+    {
+        let cancel = CancellationToken::new();
+        for cleanup in &command.on_failure {
+            on_log(format!(
+                "cleaning up after {}: {}",
+                command.program, cleanup.program
+            ));
+            let started_at = SystemTime::now();
+            let clock = Instant::now();
+            let result = executor.run_streaming(cleanup, &cancel, &mut |_, _| {});
+            if let Some(transcript) = ctx.transcript {
+                let record = TranscriptRecord::new(cleanup, started_at, clock.elapsed(), &result);
+                if let Err(err) = transcript.record(&record) {
+                    warn!("failed to record transcript entry: {:#}", err);
+                }
+            }
+            match result {
+                Ok(output) if output.success() => {}
+                Ok(output) => on_log(format!(
+                    "cleanup {} exited with status {:?}",
+                    cleanup.program,
+                    output.status.code()
+                )),
+                Err(err) => on_log(format!("cleanup {} failed: {err:#}", cleanup.program)),
+            }
+        }
+    }
+
+    /// Find the disk `expected` describes under its current kernel name. If
+    /// it moved, rewrite the device paths in `plan` so the actions that
+    /// follow write to the right device. Fails when the disk cannot be found.
+    fn verify_target<F>(
+        &self,
+        plan: &mut InstallPlan,
+        expected: &DiskIdentifier,
+        on_log: &mut F,
+    ) -> Result<()>
+    where
+        F: FnMut(String),
+    {
+        let inventory = disk::probe_inventory_with(self.executor.as_ref())?;
+        let current = disk::resolve_identity(expected, &inventory)?;
+        if current.path == expected.path {
+            on_log(format!(
+                "Target disk confirmed as {} ({})",
+                current.path,
+                disk::describe_identity(&current)
+            ));
+            return Ok(());
+        }
+
+        on_log(format!(
+            "Target disk {} moved from {} to {}; updating the plan",
+            disk::describe_identity(expected),
+            expected.path,
+            current.path
+        ));
+        plan.retarget(&expected.path, &current);
+        Ok(())
+    }
+
+    /// Run a group of independent commands at once. Output is logged per
+    /// command once it finishes; the first failure decides the result.
+    fn run_concurrently<F>(
+        &self,
+        ctx: &ExecutionContext<'_>,
+        specs: &[CommandSpec],
+        on_log: &mut F,
+    ) -> Result<Option<CommandOutput>>
+    where
+        F: FnMut(String),
+    {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .context("failed to start async runtime")?;
+        let completed = runtime.block_on(concurrent::run_concurrent(
+            Arc::clone(&self.async_executor),
+            specs.to_vec(),
+            ctx.cancel.clone(),
+        ));
+
+        let mut first_failure: Option<Result<Option<CommandOutput>>> = None;
+        for command in completed {
+            if let Some(transcript) = ctx.transcript {
+                let record = TranscriptRecord::new(
+                    &command.spec,
+                    command.started_at,
+                    command.duration,
+                    &command.result,
+                );
+                if let Err(err) = transcript.record(&record) {
+                    warn!("failed to record transcript entry: {:#}", err);
+                }
+            }
 
-            if !success {
-                break;
+            let program = command.spec.program;
+            match command.result {
+                Ok(output) => {
+                    for line in output.stdout.lines().filter(|l| !l.trim().is_empty()) {
+                        on_log(format!("[{program}] {line}"));
+                    }
+                    for line in output.stderr.lines().filter(|l| !l.trim().is_empty()) {
+                        on_log(format!("[{program}] stderr: {line}"));
+                    }
+                    if !output.success() && first_failure.is_none() {
+                        first_failure = Some(Ok(Some(output)));
+                    }
+                }
+                Err(err) => {
+                    if first_failure.is_none() {
+                        first_failure = Some(Err(err.context(format!("failed to run {program}"))));
+                    }
+                }
             }
         }
 
-        Ok(success)
+        first_failure.unwrap_or(Ok(None))
+    }
+}
+
+/// The target plan, starting from the default layout for the selected disk
+/// when there is none yet.
+fn target_or_default(state: &mut InstallerState) -> Result<&mut DiskPlan> {
+    if state.target.is_none() {
+        let disk = state
+            .selected_disk
+            .clone()
+            .context("no target disk selected")?;
+        state.target = Some(partition::default_plan_for_disk(&disk));
     }
+    state.target.as_mut().context("no target disk selected")
+}
+
+/// Sleep for `delay`, waking early if `cancel` fires. Returns `false` when
+/// the wait was cancelled.
+fn sleep_unless_cancelled(delay: Duration, cancel: &CancellationToken) -> bool {
+    const SLICE: Duration = Duration::from_millis(100);
+
+    let deadline = Instant::now() + delay;
+    while Instant::now() < deadline {
+        if cancel.is_cancelled() {
+            return false;
+        }
+        std::thread::sleep(SLICE.min(deadline.saturating_duration_since(Instant::now())));
+    }
+    !cancel.is_cancelled()
+}
+
+/// How a run of [`Backend::execute_plan_stream`] ended.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InstallOutcome {
+    Completed,
+    Failed,
+    Cancelled,
+    TimedOut { program: String, timeout: Duration },
 }
 
 impl Default for Backend {
@@ -116,6 +702,412 @@ impl Clone for Backend {
         Self {
             state: Arc::clone(&self.state),
             executor: Arc::clone(&self.executor),
+            async_executor: Arc::clone(&self.async_executor),
+            chroot_mode: self.chroot_mode,
+            transcript_dir: self.transcript_dir.clone(),
+            probe_cache: Arc::clone(&self.probe_cache),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use command::{Backoff, RetryPolicy};
+    use scripted::{CommandMatcher, ScriptedExecutor, ScriptedResponse};
+    use tasks::{InstallStage, InstallStep};
+
+    fn backend(executor: &Arc<ScriptedExecutor>, name: &str) -> Backend {
+        let transcript_dir =
+            std::env::temp_dir().join(format!("installer-test-{}-{name}", std::process::id()));
+        Backend::with_executor(
+            Arc::new(RwLock::new(InstallerState::default())),
+            Arc::clone(executor) as Arc<dyn CommandExecutor + Send + Sync>,
+        )
+        .with_transcript_dir(transcript_dir)
+    }
+
+    fn plan(actions: Vec<PlanAction>) -> InstallPlan {
+        InstallPlan::new(
+            "/nonexistent",
+            vec![InstallStep::new(
+                InstallStage::PrepareEnvironment,
+                "test step",
+                actions,
+            )],
+        )
+    }
+
+    fn run(program: &str) -> PlanAction {
+        PlanAction::Run(CommandSpec::new(program, vec!["--flag".into()]))
+    }
+
+    #[test]
+    fn plan_runs_every_action_in_order() {
+        let executor = Arc::new(
+            ScriptedExecutor::new()
+                .on(
+                    CommandMatcher::program("first").with_exact_args(["--flag"]),
+                    ScriptedResponse::stdout("hello\n"),
+                )
+                .with_fallback(ScriptedResponse::success()),
+        );
+        let plan = plan(vec![
+            run("first"),
+            PlanAction::RunConcurrently(vec![
+                CommandSpec::new("mkfs.ext4", Vec::new()),
+                CommandSpec::new("mkfs.fat", Vec::new()),
+            ]),
+            run("last"),
+        ]);
+
+        let mut log = Vec::new();
+        let outcome = backend(&executor, "order")
+            .execute_plan_stream(plan, &CancellationToken::new(), |line| log.push(line))
+            .unwrap();
+
+        assert_eq!(outcome, InstallOutcome::Completed);
+        let programs: Vec<String> = executor
+            .calls()
+            .into_iter()
+            .map(|spec| spec.program)
+            .collect();
+        assert_eq!(programs.first().map(String::as_str), Some("first"));
+        assert_eq!(programs.last().map(String::as_str), Some("last"));
+        assert_eq!(programs.len(), 4);
+        assert!(log.iter().any(|line| line.contains("hello")));
+    }
+
+    #[test]
+    fn failing_command_stops_the_plan() {
+        let executor = Arc::new(
+            ScriptedExecutor::new()
+                .on(
+                    CommandMatcher::program("sfdisk"),
+                    ScriptedResponse::exit(1).with_stderr("device busy"),
+                )
+                .with_fallback(ScriptedResponse::success()),
+        );
+        let plan = plan(vec![run("sfdisk"), run("mkfs.ext4")]);
+
+        let outcome = backend(&executor, "failure")
+            .execute_plan_stream(plan, &CancellationToken::new(), |_| {})
+            .unwrap();
+
+        assert_eq!(outcome, InstallOutcome::Failed);
+        assert_eq!(executor.call_count("mkfs.ext4"), 0);
+    }
+
+    #[test]
+    fn retry_policy_runs_the_command_again() {
+        let executor = Arc::new(
+            ScriptedExecutor::new()
+                .on_times(
+                    CommandMatcher::program("pacstrap"),
+                    ScriptedResponse::exit(1),
+                    1,
+                )
+                .on(
+                    CommandMatcher::program("pacstrap"),
+                    ScriptedResponse::success(),
+                ),
+        );
+        let policy = RetryPolicy::new(
+            3,
+            Backoff::Exponential {
+                initial: Duration::from_millis(1),
+                max: Duration::from_millis(1),
+            },
+        );
+        let plan = plan(vec![PlanAction::Run(
+            CommandSpec::new("pacstrap", Vec::new()).with_retry(policy),
+        )]);
+
+        let outcome = backend(&executor, "retry")
+            .execute_plan_stream(plan, &CancellationToken::new(), |_| {})
+            .unwrap();
+
+        assert_eq!(outcome, InstallOutcome::Completed);
+        assert_eq!(executor.call_count("pacstrap"), 2);
+    }
+
+    #[test]
+    fn failure_cleanup_runs_only_when_the_command_fails() {
+        let executor = Arc::new(
+            ScriptedExecutor::new()
+                .on(CommandMatcher::program("btrfs"), ScriptedResponse::exit(1))
+                .with_fallback(ScriptedResponse::success()),
+        );
+        let unmount = || CommandSpec::new("umount", vec!["/mnt".into()]);
+        let plan = plan(vec![
+            PlanAction::Run(CommandSpec::new("mount", Vec::new()).on_failure(unmount())),
+            PlanAction::Run(CommandSpec::new("btrfs", Vec::new()).on_failure(unmount())),
+            run("sfdisk"),
+        ]);
+
+        let outcome = backend(&executor, "cleanup")
+            .execute_plan_stream(plan, &CancellationToken::new(), |_| {})
+            .unwrap();
+
+        assert_eq!(outcome, InstallOutcome::Failed);
+        assert_eq!(executor.call_count("umount"), 1);
+        assert_eq!(executor.call_count("sfdisk"), 0);
+    }
+
+    #[test]
+    fn renamed_target_is_followed_by_every_command() {
+        let disk = DiskIdentifier {
+            path: "/dev/sda".into(),
+            size_bytes: 64 * 1024 * 1024 * 1024,
+            label: None,
+            geometry: DiskGeometry::default(),
+            serial: Some("S1".into()),
+            wwn: None,
+            by_id: None,
+        };
+        let state = InstallerState {
+            selected_disk: Some(disk.clone()),
+            ..InstallerState::default()
+        };
+        let full = build_plan(&state).unwrap();
+        let steps = full
+            .steps()
+            .iter()
+            .filter(|step| {
+                matches!(
+                    step.stage,
+                    InstallStage::PartitionDisks
+                        | InstallStage::FormatFilesystems
+                        | InstallStage::MountTarget
+                )
+            })
+            .cloned()
+            .collect();
+        let executor = Arc::new(
+            ScriptedExecutor::new()
+                .on(
+                    CommandMatcher::program("lsblk"),
+                    ScriptedResponse::stdout(
+                        r#"{"blockdevices": [{"name": "sdb", "path": "/dev/sdb",
+                            "size": 68719476736, "type": "disk", "serial": "S1"}]}"#,
+                    ),
+                )
+                .with_fallback(ScriptedResponse::success()),
+        );
+        let backend = backend(&executor, "retarget");
+        *backend.state.write() = state;
+
+        let mut log = Vec::new();
+        let outcome = backend
+            .execute_plan_stream(
+                InstallPlan::new("/nonexistent", steps),
+                &CancellationToken::new(),
+                |line| log.push(line),
+            )
+            .unwrap();
+
+        assert_eq!(outcome, InstallOutcome::Completed);
+        assert!(log
+            .iter()
+            .any(|line| line.contains("moved from /dev/sda to /dev/sdb")));
+        let calls: Vec<CommandSpec> = executor
+            .calls()
+            .into_iter()
+            .filter(|spec| spec.program != "lsblk")
+            .collect();
+        let mentions = |device: &str| {
+            calls.iter().any(|spec| {
+                let script = spec
+                    .stdin
+                    .as_ref()
+                    .map(|stdin| String::from_utf8_lossy(stdin.as_bytes()).into_owned())
+                    .unwrap_or_default();
+                spec.args.iter().any(|arg| arg.starts_with(device)) || script.contains(device)
+            })
+        };
+        assert!(!mentions("/dev/sda"));
+        let sfdisk = calls.iter().find(|spec| spec.program == "sfdisk").unwrap();
+        assert_eq!(sfdisk.args.last().map(String::as_str), Some("/dev/sdb"));
+        let script = String::from_utf8_lossy(sfdisk.stdin.as_ref().unwrap().as_bytes());
+        assert!(script.contains("/dev/sdb1 : "));
+        assert!(script.contains("/dev/sdb2 : "));
+        for device in ["/dev/sdb1", "/dev/sdb2"] {
+            assert!(
+                calls
+                    .iter()
+                    .any(|spec| spec.program.starts_with("mkfs")
+                        && spec.args.contains(&device.into()))
+            );
+        }
+        // The selection the UI shows is left alone.
+        assert_eq!(
+            backend
+                .state
+                .read()
+                .selected_disk
+                .as_ref()
+                .map(|d| d.path.as_str()),
+            Some("/dev/sda")
+        );
+    }
+
+    #[test]
+    fn root_size_finds_a_root_written_with_a_trailing_slash() {
+        let disk = DiskIdentifier {
+            path: "/dev/sda".into(),
+            size_bytes: 64 * 1024 * 1024 * 1024,
+            label: None,
+            geometry: DiskGeometry::default(),
+            serial: None,
+            wwn: None,
+            by_id: None,
+        };
+        let mut target = partition::default_plan_for_disk(&disk);
+        for spec in &mut target.partitions {
+            if spec.mountpoint.as_deref() == Some("/") {
+                spec.mountpoint = Some("//".into());
+            }
+        }
+        let executor = Arc::new(ScriptedExecutor::new());
+        let backend = backend(&executor, "root-size");
+        *backend.state.write() = InstallerState {
+            selected_disk: Some(disk),
+            target: Some(target),
+            ..InstallerState::default()
+        };
+
+        let errors = backend
+            .set_root_size(PartitionSize::ExactBytes(16 * 1024 * 1024 * 1024))
+            .unwrap();
+
+        assert!(errors.is_empty());
+        let state = backend.state.read();
+        let root = &state.target.as_ref().unwrap().partitions[1];
+        assert_eq!(
+            root.size,
+            PartitionSize::ExactBytes(16 * 1024 * 1024 * 1024)
+        );
+    }
+
+    #[test]
+    fn cancelling_kills_the_running_command_and_its_children() {
+        let dir =
+            std::env::temp_dir().join(format!("installer-test-{}-cancel", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let pid_file = dir.join("sleep.pid");
+        let backend = Backend::with_executor(
+            Arc::new(RwLock::new(InstallerState::default())),
+            Arc::new(SystemCommandExecutor),
+        )
+        .with_transcript_dir(&dir);
+        let plan = plan(vec![
+            PlanAction::Run(CommandSpec::new(
+                "sh",
+                vec![
+                    "-c".into(),
+                    format!("sleep 30 & echo $! > {}; wait", pid_file.display()),
+                ],
+            )),
+            PlanAction::Run(CommandSpec::new(
+                "touch",
+                vec![dir.join("ran").display().to_string()],
+            )),
+        ]);
+        let cancel = CancellationToken::new();
+        let canceller = cancel.clone();
+        let watched = pid_file.clone();
+        std::thread::spawn(move || {
+            while !watched.exists() {
+                std::thread::sleep(Duration::from_millis(20));
+            }
+            std::thread::sleep(Duration::from_millis(100));
+            canceller.cancel();
+        });
+
+        let started = Instant::now();
+        let outcome = backend.execute_plan_stream(plan, &cancel, |_| {}).unwrap();
+
+        assert_eq!(outcome, InstallOutcome::Cancelled);
+        assert!(started.elapsed() < Duration::from_secs(10));
+        assert!(!dir.join("ran").exists());
+        // The background sleep shares the process group and goes with it.
+        let pid = std::fs::read_to_string(&pid_file).unwrap();
+        let stat = format!("/proc/{}/stat", pid.trim());
+        let gone = (0..100).any(|_| {
+            let alive = std::fs::read_to_string(&stat).is_ok_and(|stat| !stat.contains(") Z "));
+            if alive {
+                std::thread::sleep(Duration::from_millis(50));
+            }
+            !alive
+        });
+        let _ = std::fs::remove_dir_all(&dir);
+        assert!(gone, "sleep {} survived the cancel", pid.trim());
+    }
+
+    #[test]
+    fn missing_tool_aborts_before_anything_runs() {
+        let executor = Arc::new(ScriptedExecutor::new().with_fallback(ScriptedResponse::success()));
+        let plan = plan(vec![
+            PlanAction::CheckTools,
+            run("sfdisk"),
+            run("installer-test-missing-tool"),
+        ]);
+
+        let mut log = Vec::new();
+        let outcome = backend(&executor, "missing-tool")
+            .execute_plan_stream(plan, &CancellationToken::new(), |line| log.push(line))
+            .unwrap();
+
+        assert_eq!(outcome, InstallOutcome::Failed);
+        assert_eq!(executor.call_count("sfdisk"), 0);
+        assert_eq!(executor.call_count("installer-test-missing-tool"), 0);
+        assert!(log
+            .iter()
+            .any(|line| line.contains("installer-test-missing-tool")));
+    }
+
+    #[test]
+    fn wrong_target_disk_aborts_before_partitioning() {
+        let expected = DiskIdentifier {
+            path: "/dev/sda".into(),
+            size_bytes: 68719476736,
+            label: None,
+            geometry: DiskGeometry::default(),
+            serial: Some("S1".into()),
+            wwn: None,
+            by_id: None,
+        };
+        let attached = [
+            // Another disk now sits at the old name.
+            r#"{"blockdevices": [{"name": "sda", "path": "/dev/sda",
+                "size": 68719476736, "type": "disk", "serial": "S2"}]}"#,
+            // The same disk reports a different size.
+            r#"{"blockdevices": [{"name": "sda", "path": "/dev/sda",
+                "size": 137438953472, "type": "disk", "serial": "S1"}]}"#,
+        ];
+
+        for lsblk in attached {
+            let executor = Arc::new(
+                ScriptedExecutor::new()
+                    .on(
+                        CommandMatcher::program("lsblk"),
+                        ScriptedResponse::stdout(lsblk),
+                    )
+                    .with_fallback(ScriptedResponse::success()),
+            );
+            let plan = plan(vec![
+                PlanAction::VerifyTarget(expected.clone()),
+                run("sfdisk"),
+                run("mkfs.ext4"),
+            ]);
+
+            let outcome = backend(&executor, "wrong-target")
+                .execute_plan_stream(plan, &CancellationToken::new(), |_| {})
+                .unwrap();
+
+            assert_eq!(outcome, InstallOutcome::Failed);
+            assert_eq!(executor.call_count("sfdisk"), 0);
+            assert_eq!(executor.call_count("mkfs.ext4"), 0);
         }
     }
 }
@@ -0,0 +1,373 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use tracing::{debug, warn};
+
+use crate::backend::command::{CommandExecutor, CommandSpec};
+use crate::state::{BlockNode, DiskInventory, ExistingSystem, ExistingSystemKind, Ineligibility};
+
+/// Scratch directory where unmounted filesystems are mounted read-only while
+/// looking for an os-release file.
+pub const PROBE_MOUNT_DIR: &str = "/run/arm-installer/os-probe";
+
+const MOUNT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// GPT partition type of an APFS container.
+const APFS_PARTTYPE: &str = "7c3457ef-0000-11aa-aa11-00306543ecac";
+
+const LINUX_FILESYSTEMS: &[&str] = &["ext2", "ext3", "ext4", "btrfs", "xfs", "f2fs"];
+
+/// Where os-release may live relative to a filesystem root. The `@` entries
+/// cover the common btrfs layout with the root in a subvolume.
+const OS_RELEASE_PATHS: &[&str] = &[
+    "etc/os-release",
+    "usr/lib/os-release",
+    "@/etc/os-release",
+    "@/usr/lib/os-release",
+];
+
+/// os-release names already read from unmounted filesystems, so a rescan
+/// after a hotplug event only mounts filesystems it has not seen before.
+/// Filesystems are keyed by UUID rather than device node, which keeps the
+/// entries valid when a disk comes back under another kernel name.
+#[derive(Debug, Default)]
+pub struct ProbeCache {
+    names: HashMap<FilesystemKey, Option<String>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct FilesystemKey {
+    uuid: String,
+    filesystem: String,
+    size_bytes: u64,
+}
+
+impl FilesystemKey {
+    fn of(node: &BlockNode, filesystem: &str) -> Option<Self> {
+        Some(Self {
+            uuid: node.uuid.clone()?,
+            filesystem: filesystem.to_string(),
+            size_bytes: node.size_bytes,
+        })
+    }
+}
+
+/// Fill in `existing_systems` for every disk that could be picked as a
+/// target. Probe failures are logged and otherwise ignored; successful
+/// probes are remembered in `cache`.
+pub fn detect_existing_systems(
+    inventory: &mut DiskInventory,
+    executor: &dyn CommandExecutor,
+    mount_dir: &Path,
+    cache: &mut ProbeCache,
+) {
+    for disk in &mut inventory.disks {
+        if matches!(
+            disk.ineligible,
+            Some(Ineligibility::LiveMedium | Ineligibility::Zram)
+        ) {
+            continue;
+        }
+
+        let found: Vec<ExistingSystem> = disk
+            .descendants()
+            .into_iter()
+            .filter_map(|node| detect_on_node(node, executor, mount_dir, cache))
+            .collect();
+        disk.existing_systems = found;
+    }
+}
+
+pub fn detect_on_node(
+    node: &BlockNode,
+    executor: &dyn CommandExecutor,
+    mount_dir: &Path,
+    cache: &mut ProbeCache,
+) -> Option<ExistingSystem> {
+    let filesystem = node
+        .filesystem
+        .as_deref()
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    let parttype = node
+        .parttype
+        .as_deref()
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    let found = |kind, name: String| {
+        Some(ExistingSystem {
+            kind,
+            path: node.path.clone(),
+            name,
+        })
+    };
+
+    if filesystem == "apfs" || parttype == APFS_PARTTYPE {
+        return found(ExistingSystemKind::MacOs, "macOS (APFS container)".into());
+    }
+    if filesystem == "bitlocker" {
+        return found(
+            ExistingSystemKind::BitLocker,
+            "BitLocker encrypted volume".into(),
+        );
+    }
+    if filesystem == "ntfs" {
+        let name = match &node.label {
+            Some(label) => format!("Windows NTFS volume \"{label}\""),
+            None => "Windows NTFS volume".into(),
+        };
+        return found(ExistingSystemKind::Windows, name);
+    }
+    if LINUX_FILESYSTEMS.contains(&filesystem.as_str()) {
+        let key = FilesystemKey::of(node, &filesystem);
+        let name = match key.as_ref().and_then(|key| cache.names.get(key)) {
+            Some(name) => Ok(name.clone()),
+            None => read_os_release(node, &filesystem, executor, mount_dir),
+        };
+        if let (Some(key), Ok(name)) = (key, &name) {
+            cache.names.insert(key, name.clone());
+        }
+        return match name {
+            Ok(Some(name)) => found(ExistingSystemKind::Linux, name),
+            Ok(None) => None,
+            Err(err) => {
+                warn!(
+                    path = node.path,
+                    "failed to look for an installed system: {:#}", err
+                );
+                None
+            }
+        };
+    }
+
+    None
+}
+
+/// The `PRETTY_NAME` (or `NAME`) of the system on `node`, mounting it
+/// read-only under `mount_dir` when it is not mounted already.
+fn read_os_release(
+    node: &BlockNode,
+    filesystem: &str,
+    executor: &dyn CommandExecutor,
+    mount_dir: &Path,
+) -> Result<Option<String>> {
+    if let Some(mountpoint) = node.mountpoints.first() {
+        return Ok(find_os_release(Path::new(mountpoint)));
+    }
+
+    let target = mount_dir.join(&node.name);
+    fs::create_dir_all(&target)
+        .with_context(|| format!("failed to create {}", target.display()))?;
+    let target_arg = target.to_string_lossy().into_owned();
+
+    let mount = CommandSpec::new(
+        "mount",
+        vec![
+            "-o".into(),
+            read_only_options(filesystem).into(),
+            node.path.clone(),
+            target_arg.clone(),
+        ],
+    )
+    .with_timeout(MOUNT_TIMEOUT);
+    let output = executor.run(&mount)?;
+    if !output.success() {
+        let _ = fs::remove_dir(&target);
+        bail!(
+            "mount exited with {}: {}",
+            output.status,
+            output.stderr.trim()
+        );
+    }
+
+    let name = find_os_release(&target);
+    debug!(path = node.path, ?name, "probed filesystem for os-release");
+
+    let umount = CommandSpec::new("umount", vec![target_arg]).with_timeout(MOUNT_TIMEOUT);
+    match executor.run(&umount) {
+        Ok(output) if output.success() => {
+            let _ = fs::remove_dir(&target);
+        }
+        Ok(output) => warn!(
+            path = node.path,
+            "failed to unmount probe mount: {}",
+            output.stderr.trim()
+        ),
+        Err(err) => warn!(path = node.path, "failed to unmount probe mount: {:#}", err),
+    }
+
+    Ok(name)
+}
+
+/// Plain `ro` still replays the journal on ext3/4, xfs and btrfs, which
+/// writes to the disk.
+fn read_only_options(filesystem: &str) -> &'static str {
+    match filesystem {
+        "ext3" | "ext4" => "ro,noload",
+        "xfs" => "ro,norecovery",
+        "btrfs" => "ro,rescue=nologreplay",
+        _ => "ro",
+    }
+}
+
+fn find_os_release(root: &Path) -> Option<String> {
+    OS_RELEASE_PATHS.iter().find_map(|relative| {
+        let path = root.join(relative);
+        // An absolute symlink would resolve against the live system.
+        let meta = fs::symlink_metadata(&path).ok()?;
+        if meta.file_type().is_symlink() {
+            let link = fs::read_link(&path).ok()?;
+            if link.is_absolute() {
+                return None;
+            }
+        }
+        parse_os_release(&fs::read_to_string(&path).ok()?)
+    })
+}
+
+pub fn parse_os_release(contents: &str) -> Option<String> {
+    let value = |key: &str| {
+        contents
+            .lines()
+            .find_map(|line| {
+                let (name, value) = line.trim().split_once('=')?;
+                (name == key).then(|| {
+                    value
+                        .trim()
+                        .trim_matches('"')
+                        .trim_matches('\'')
+                        .to_string()
+                })
+            })
+            .filter(|value| !value.is_empty())
+    };
+
+    value("PRETTY_NAME").or_else(|| value("NAME"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::scripted::{CommandMatcher, ScriptedExecutor, ScriptedResponse};
+
+    fn linux_partition(path: &str) -> BlockNode {
+        BlockNode {
+            name: path.trim_start_matches("/dev/").into(),
+            path: path.into(),
+            kind: "part".into(),
+            size_bytes: 32 * 1024 * 1024 * 1024,
+            filesystem: Some("ext4".into()),
+            label: None,
+            partlabel: None,
+            parttype: None,
+            uuid: Some("0b7e1c9a-5f43-4d1e-9a53-2c1f6f0d8e11".into()),
+            partuuid: None,
+            mountpoints: Vec::new(),
+            read_only: false,
+            start_bytes: Some(1024 * 1024),
+            children: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn os_release_prefers_the_pretty_name() {
+        let cases = [
+            (
+                "NAME=\"Arch Linux\"\nPRETTY_NAME=\"Arch Linux ARM\"\n",
+                "Arch Linux ARM",
+            ),
+            ("PRETTY_NAME='Fedora Linux 41'\n", "Fedora Linux 41"),
+            ("PRETTY_NAME=Debian\n", "Debian"),
+            ("  PRETTY_NAME=\"Indented\"  \n", "Indented"),
+            ("# PRETTY_NAME=\"Commented\"\nNAME=Alpine\n", "Alpine"),
+            ("ID=ubuntu\nNAME=\"Ubuntu\"\n", "Ubuntu"),
+            ("PRETTY_NAME=\"\"\nNAME=openSUSE\n", "openSUSE"),
+            (
+                "VERSION=\"1 (PRETTY_NAME=no)\"\nPRETTY_NAME=\"x=y\"\n",
+                "x=y",
+            ),
+        ];
+
+        for (contents, expected) in cases {
+            assert_eq!(
+                parse_os_release(contents).as_deref(),
+                Some(expected),
+                "{contents:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn os_release_without_a_name_is_ignored() {
+        assert_eq!(parse_os_release(""), None);
+        assert_eq!(parse_os_release("ID=arch\nVERSION_ID=rolling\n"), None);
+        assert_eq!(parse_os_release("PRETTY_NAME=\nNAME=\"\"\n"), None);
+        assert_eq!(parse_os_release("# NAME=Arch\n"), None);
+    }
+
+    #[test]
+    fn cached_filesystems_are_not_mounted_again() {
+        let mount_dir =
+            std::env::temp_dir().join(format!("installer-os-probe-{}", std::process::id()));
+        let release = mount_dir.join("sdb2/etc");
+        fs::create_dir_all(&release).unwrap();
+        fs::write(
+            release.join("os-release"),
+            "PRETTY_NAME=\"Arch Linux ARM\"\n",
+        )
+        .unwrap();
+        let executor = ScriptedExecutor::new()
+            .on(
+                CommandMatcher::program("mount"),
+                ScriptedResponse::success(),
+            )
+            .on(
+                CommandMatcher::program("umount"),
+                ScriptedResponse::success(),
+            );
+        let mut cache = ProbeCache::default();
+
+        let first = detect_on_node(
+            &linux_partition("/dev/sdb2"),
+            &executor,
+            &mount_dir,
+            &mut cache,
+        )
+        .unwrap();
+        // The same filesystem after the disk came back as sdc.
+        let second = detect_on_node(
+            &linux_partition("/dev/sdc2"),
+            &executor,
+            &mount_dir,
+            &mut cache,
+        )
+        .unwrap();
+        let _ = fs::remove_dir_all(&mount_dir);
+
+        assert_eq!(first.name, "Arch Linux ARM");
+        assert_eq!(second.name, "Arch Linux ARM");
+        assert_eq!(second.path, "/dev/sdc2");
+        assert_eq!(executor.call_count("mount"), 1);
+    }
+
+    #[test]
+    fn failed_probes_are_retried() {
+        let mount_dir =
+            std::env::temp_dir().join(format!("installer-os-probe-retry-{}", std::process::id()));
+        let executor = ScriptedExecutor::new().on(
+            CommandMatcher::program("mount"),
+            ScriptedResponse::exit(32).with_stderr("wrong fs type"),
+        );
+        let mut cache = ProbeCache::default();
+        let node = linux_partition("/dev/sdb2");
+
+        assert!(detect_on_node(&node, &executor, &mount_dir, &mut cache).is_none());
+        assert!(detect_on_node(&node, &executor, &mount_dir, &mut cache).is_none());
+        let _ = fs::remove_dir_all(&mount_dir);
+
+        assert_eq!(executor.call_count("mount"), 2);
+    }
+}
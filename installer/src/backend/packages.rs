@@ -1,4 +1,11 @@
-use crate::backend::command::CommandSpec;
+use std::time::Duration;
+
+use crate::backend::command::{Backoff, CommandSpec, RetryPolicy};
+
+/// pacstrap exits with 1 when a mirror download fails, which is usually
+/// transient.
+const PACSTRAP_ATTEMPTS: u32 = 3;
+const PACSTRAP_RETRYABLE_EXIT_CODES: &[i32] = &[1];
 
 const BASE_PACKAGES: &[&str] = &[
     "base",
@@ -22,7 +29,16 @@ pub fn pacstrap_command(root: &str, packages: &[&str]) -> CommandSpec {
     let mut args = Vec::with_capacity(2 + packages.len());
     args.push(root.into());
     args.extend(packages.iter().map(|pkg| pkg.to_string()));
-    CommandSpec::new("pacstrap", args)
+    CommandSpec::new("pacstrap", args).with_retry(
+        RetryPolicy::new(
+            PACSTRAP_ATTEMPTS,
+            Backoff::Exponential {
+                initial: Duration::from_secs(10),
+                max: Duration::from_secs(60),
+            },
+        )
+        .with_retryable_exit_codes(PACSTRAP_RETRYABLE_EXIT_CODES),
+    )
 }
 
 pub fn install_base_packages(root: &str) -> CommandSpec {
@@ -33,22 +49,20 @@ pub fn install_desktop_packages(root: &str) -> CommandSpec {
     pacstrap_command(root, DESKTOP_PACKAGES)
 }
 
-pub fn enable_services_commands(root: &str) -> Vec<CommandSpec> {
+/// Service units to enable, as commands to run inside the target root.
+pub fn enable_services_commands() -> Vec<CommandSpec> {
     vec![
-        systemctl_enable_command(root, "gdm.service"),
-        systemctl_enable_command(root, "NetworkManager.service"),
-        systemctl_enable_command(root, "sshd.service"),
+        systemctl_enable_command("gdm.service"),
+        systemctl_enable_command("NetworkManager.service"),
+        systemctl_enable_command("sshd.service"),
     ]
 }
 
-fn systemctl_enable_command(root: &str, service: &str) -> CommandSpec {
-    CommandSpec::new(
-        "arch-chroot",
-        vec![
-            root.into(),
-            "systemctl".into(),
-            "enable".into(),
-            service.into(),
-        ],
-    )
+/// Weekly TRIM of all mounted filesystems that support it.
+pub fn enable_fstrim_command() -> CommandSpec {
+    systemctl_enable_command("fstrim.timer")
+}
+
+fn systemctl_enable_command(service: &str) -> CommandSpec {
+    CommandSpec::new("systemctl", vec!["enable".into(), service.into()])
 }
@@ -1,27 +1,257 @@
+use std::collections::HashSet;
+
 use anyhow::{anyhow, bail, Context, Result};
+use thiserror::Error;
 
-use crate::backend::command::CommandSpec;
 use crate::state::{
-    DiskIdentifier, DiskMode, DiskPlan, FileSystem, PartitionFlag, PartitionSize, PartitionSpec,
+    DiscardPolicy, DiskGeometry, DiskIdentifier, DiskMode, DiskPlan, FileSystem, FreeSpaceRegion,
+    InstallerState, PartitionFlag, PartitionSize, PartitionSpec,
 };
 
 const MIB: u64 = 1024 * 1024;
 
-pub fn validate_plan(disk: &DiskIdentifier, plan: &DiskPlan) -> Result<()> {
+// Type GUIDs from the Discoverable Partitions Specification, which lets
+// systemd-gpt-auto-generator find and mount the partitions without an fstab.
+pub const ESP_TYPE_GUID: &str = "C12A7328-F81F-11D2-BA4B-00A0C93EC93B";
+pub const XBOOTLDR_TYPE_GUID: &str = "BC13C2FF-59E6-4262-A352-B275FD6F7172";
+pub const ROOT_ARM64_TYPE_GUID: &str = "B921B045-1DF0-41C3-AF44-4C6F280D3FAE";
+pub const HOME_TYPE_GUID: &str = "933AC7E1-2EB4-4F13-B844-0E14E2AEF915";
+pub const SRV_TYPE_GUID: &str = "3B8F8425-20E0-4F3B-907F-1A25A76F98E8";
+pub const SWAP_TYPE_GUID: &str = "0657FD6D-A4AB-43C4-84E5-0933C84B4F4F";
+pub const LVM_TYPE_GUID: &str = "E6D6D379-F507-44C2-A23C-238F2A3DF928";
+/// Generic Linux data, for partitions the specification has no type for.
+pub const LINUX_FILESYSTEM_TYPE_GUID: &str = "0FC63DAF-8483-4772-8E79-3D69D8477DE4";
+
+/// Size of the partition entry array in each copy of a GPT: 128 entries of
+/// 128 bytes, as written by sfdisk and parted.
+const GPT_ENTRY_ARRAY_BYTES: u64 = 128 * 128;
+
+/// Devices reporting odd optimal I/O sizes (some RAID controllers) would
+/// otherwise push partitions onto huge boundaries.
+const MAX_ALIGNMENT_BYTES: u64 = 64 * MIB;
+
+/// Smallest EFI system partition accepted; firmware and FAT32 tooling get
+/// unreliable below this.
+pub const MIN_ESP_BYTES: u64 = 300 * MIB;
+
+/// A reason a [`DiskPlan`] cannot be applied.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum PlanError {
+    #[error("the plan is for {plan}, but {selected} is selected")]
+    DiskMismatch { selected: String, plan: String },
+    #[error("the layout has no partitions")]
+    NoPartitions,
+    #[error("the partitions need {required_mib} MiB but only {available_mib} MiB are usable")]
+    Overflow {
+        required_mib: u64,
+        available_mib: u64,
+    },
+    #[error("no partition is mounted at /")]
+    MissingRoot,
+    #[error("the layout has no EFI system partition")]
+    MissingEsp,
+    #[error("EFI system partition {id} is {size_mib} MiB, it needs at least {} MiB", MIN_ESP_BYTES / MIB)]
+    EspTooSmall { id: String, size_mib: u64 },
+    #[error("{mountpoint} is mounted more than once")]
+    DuplicateMountpoint { mountpoint: String },
+    #[error("partition name {id} is used more than once")]
+    DuplicateId { id: String },
+    #[error("swap partition {id} cannot be mounted at {mountpoint}")]
+    SwapWithMountpoint { id: String, mountpoint: String },
+    #[error("percentage sizes add up to {total}%")]
+    PercentageOverflow { total: u32 },
+    #[error("only one partition can take the remaining space")]
+    MultipleRemainders,
+    #[error("partition {id} has an invalid size: {reason}")]
+    InvalidSize { id: String, reason: String },
+    #[error("{0}")]
+    UnusableSpace(String),
+}
+
+/// Check `plan` against everything that would stop it from being applied,
+/// reporting every problem rather than just the first.
+pub fn validate_plan(disk: &DiskIdentifier, plan: &DiskPlan) -> Vec<PlanError> {
+    let mut errors = Vec::new();
+
     if plan.target.path != disk.path {
-        bail!("disk mismatch between selection and plan");
+        errors.push(PlanError::DiskMismatch {
+            selected: disk.path.clone(),
+            plan: plan.target.path.clone(),
+        });
     }
-
     if plan.partitions.is_empty() {
-        bail!("partition plan must contain at least one partition");
+        errors.push(PlanError::NoPartitions);
+        return errors;
+    }
+
+    let percentage_total: u32 = plan
+        .partitions
+        .iter()
+        .filter_map(|spec| match spec.size {
+            PartitionSize::Percentage(percent) => Some(u32::from(percent)),
+            _ => None,
+        })
+        .sum();
+    if percentage_total > 100 {
+        errors.push(PlanError::PercentageOverflow {
+            total: percentage_total,
+        });
+    }
+    if plan
+        .partitions
+        .iter()
+        .filter(|spec| spec.size == PartitionSize::Remainder)
+        .count()
+        > 1
+    {
+        errors.push(PlanError::MultipleRemainders);
+    }
+
+    let spans = match available_mib(plan) {
+        Ok(available) => check_space(plan, available, &mut errors),
+        Err(err) => {
+            errors.push(PlanError::UnusableSpace(format!("{err:#}")));
+            vec![None; plan.partitions.len()]
+        }
+    };
+
+    if !plan
+        .partitions
+        .iter()
+        .any(|spec| spec.mountpoint.as_deref().map(normalize_mountpoint) == Some("/"))
+    {
+        errors.push(PlanError::MissingRoot);
+    }
+
+    match plan
+        .partitions
+        .iter()
+        .zip(&spans)
+        .find(|(spec, _)| is_esp(spec))
+    {
+        None => errors.push(PlanError::MissingEsp),
+        Some((spec, Some(span_mib))) if span_mib * MIB < MIN_ESP_BYTES => {
+            errors.push(PlanError::EspTooSmall {
+                id: spec.id.clone(),
+                size_mib: *span_mib,
+            });
+        }
+        Some(_) => {}
+    }
+
+    let mut ids = HashSet::new();
+    let mut mountpoints = HashSet::new();
+    for spec in &plan.partitions {
+        if !ids.insert(spec.id.as_str()) {
+            errors.push(PlanError::DuplicateId {
+                id: spec.id.clone(),
+            });
+        }
+        let Some(mountpoint) = &spec.mountpoint else {
+            continue;
+        };
+        if spec.filesystem == FileSystem::Swap {
+            errors.push(PlanError::SwapWithMountpoint {
+                id: spec.id.clone(),
+                mountpoint: mountpoint.clone(),
+            });
+        } else if !mountpoints.insert(normalize_mountpoint(mountpoint)) {
+            errors.push(PlanError::DuplicateMountpoint {
+                mountpoint: normalize_mountpoint(mountpoint).to_string(),
+            });
+        }
+    }
+
+    errors
+}
+
+/// `mountpoint` without trailing slashes, so `/home/` and `/home` compare
+/// equal. `/` stays as it is.
+pub fn normalize_mountpoint(mountpoint: &str) -> &str {
+    match mountpoint.trim_end_matches('/') {
+        "" => "/",
+        trimmed => trimmed,
+    }
+}
+
+/// Problems with the target plan in `state`, checked against the selected
+/// disk. Empty when there is no target plan.
+pub fn validate_target(state: &InstallerState) -> Vec<PlanError> {
+    match &state.target {
+        Some(plan) => validate_plan(state.selected_disk.as_ref().unwrap_or(&plan.target), plan),
+        None => Vec::new(),
+    }
+}
+
+/// Point the selection and target plan in `state` at `disk`, which is the
+/// same disk under a new kernel name. Partitions being shrunk keep their
+/// numbers and move with it.
+pub fn retarget(state: &mut InstallerState, disk: &DiskIdentifier) {
+    state.selected_disk = Some(disk.clone());
+    if let Some(plan) = &mut state.target {
+        plan.target = disk.clone();
+        for resize in &mut plan.resizes {
+            resize.path = partition_device_path(&disk.path, resize.number);
+        }
+    }
+}
+
+/// Add up the space the partitions take in the same way as
+/// [`compute_ranges`], reporting invalid sizes and overflow. Returns the
+/// span of each partition where it is known.
+fn check_space(
+    plan: &DiskPlan,
+    (first_mib, end_mib): (u64, u64),
+    errors: &mut Vec<PlanError>,
+) -> Vec<Option<u64>> {
+    let align_mib = alignment_bytes(&plan.target.geometry) / MIB;
+    let available_mib = end_mib.saturating_sub(first_mib);
+
+    let mut spans: Vec<Option<u64>> = plan
+        .partitions
+        .iter()
+        .map(|spec| match spec.size {
+            PartitionSize::Remainder => None,
+            _ => match partition_size_to_mib(&spec.size, available_mib) {
+                Ok(span_mib) => Some(span_mib.next_multiple_of(align_mib)),
+                Err(err) => {
+                    errors.push(PlanError::InvalidSize {
+                        id: spec.id.clone(),
+                        reason: format!("{err:#}"),
+                    });
+                    None
+                }
+            },
+        })
+        .collect();
+
+    let fixed_mib: u64 = spans.iter().flatten().sum();
+    let remainder = plan
+        .partitions
+        .iter()
+        .position(|spec| spec.size == PartitionSize::Remainder);
+    // A remainder partition needs at least one aligned unit of its own.
+    let required_mib = fixed_mib + if remainder.is_some() { align_mib } else { 0 };
+    if required_mib > available_mib {
+        errors.push(PlanError::Overflow {
+            required_mib,
+            available_mib,
+        });
+    } else if let Some(index) = remainder {
+        spans[index] = Some(available_mib - fixed_mib);
     }
 
-    Ok(())
+    spans
 }
 
-#[derive(Debug, Default)]
-pub struct PartitionCommandPlan {
-    pub commands: Vec<CommandSpec>,
+fn is_esp(spec: &PartitionSpec) -> bool {
+    spec.flags
+        .iter()
+        .any(|flag| matches!(flag, PartitionFlag::Esp))
+        || matches!(
+            spec.mountpoint.as_deref().map(normalize_mountpoint),
+            Some("/boot/efi" | "/efi")
+        )
 }
 
 pub fn default_plan_for_disk(disk: &DiskIdentifier) -> DiskPlan {
@@ -45,69 +275,31 @@ pub fn default_plan_for_disk(disk: &DiskIdentifier) -> DiskPlan {
         target: disk.clone(),
         mode: DiskMode::UseEntireDisk,
         partitions: vec![boot_partition, root_partition],
+        discard: DiscardPolicy::for_geometry(&disk.geometry),
+        resizes: Vec::new(),
     }
 }
 
-pub fn build_command_plan(plan: &DiskPlan) -> Result<PartitionCommandPlan> {
-    let mut commands = Vec::new();
-    let disk_path = plan.target.path.clone();
-
-    // Create a GPT label by default; later this may vary per target requirements.
-    commands.push(CommandSpec::new(
-        "parted",
-        vec![
-            disk_path.clone(),
-            "--script".into(),
-            "mklabel".into(),
-            "gpt".into(),
-        ],
-    ));
-
-    let ranges = compute_ranges(plan)?;
-
-    for (index, (spec, range)) in plan.partitions.iter().zip(ranges.iter()).enumerate() {
-        let mut args = vec![
-            disk_path.clone(),
-            "--script".into(),
-            "mkpart".into(),
-            spec.id.clone(),
-        ];
-
-        if let Some(hint) = filesystem_hint(&spec.filesystem) {
-            args.push(hint.into());
-        }
-
-        args.push(format!("{}MiB", range.start_mib));
-        args.push(format!("{}MiB", range.end_mib));
-
-        commands.push(CommandSpec::new("parted", args));
-
-        for flag in &spec.flags {
-            if let Some(flag_name) = flag_name(flag) {
-                commands.push(CommandSpec::new(
-                    "parted",
-                    vec![
-                        disk_path.clone(),
-                        "--script".into(),
-                        "set".into(),
-                        (index + 1).to_string(),
-                        flag_name.into(),
-                        "on".into(),
-                    ],
-                ));
-            }
-        }
+/// The default layout placed inside an existing gap instead of across the
+/// whole disk.
+pub fn plan_for_free_space(disk: &DiskIdentifier, region: &FreeSpaceRegion) -> DiskPlan {
+    DiskPlan {
+        mode: DiskMode::FreeSpace(region.clone()),
+        ..default_plan_for_disk(disk)
     }
-
-    Ok(PartitionCommandPlan { commands })
 }
 
-pub fn apply_plan(plan: &DiskPlan, run_cmd: &dyn Fn(&CommandSpec) -> Result<()>) -> Result<()> {
-    let command_plan = build_command_plan(plan)?;
-    for command in command_plan.commands {
-        run_cmd(&command)?;
-    }
-    Ok(())
+/// Partition number each planned partition will get, in plan order.
+pub fn partition_numbers(plan: &DiskPlan) -> Vec<u32> {
+    let used: &[u32] = match &plan.mode {
+        DiskMode::FreeSpace(region) => &region.used_numbers,
+        DiskMode::UseEntireDisk | DiskMode::Custom => &[],
+    };
+
+    (1u32..)
+        .filter(|number| !used.contains(number))
+        .take(plan.partitions.len())
+        .collect()
 }
 
 pub fn describe_partition(spec: &PartitionSpec) -> String {
@@ -118,41 +310,137 @@ pub fn describe_partition(spec: &PartitionSpec) -> String {
     format!("{}: {} ({})", spec.id, mountpoint, spec.filesystem.label())
 }
 
-fn filesystem_hint(fs: &FileSystem) -> Option<&'static str> {
-    match fs {
-        FileSystem::Ext4 => Some("ext4"),
-        FileSystem::Btrfs => Some("btrfs"),
-        FileSystem::Xfs => Some("xfs"),
-        FileSystem::Swap => None,
-        FileSystem::Fat32 => Some("fat32"),
-        FileSystem::Other(_) => None,
+/// Device node of partition `number` on `disk_path`.
+pub fn partition_device_path(disk_path: &str, number: u32) -> String {
+    if disk_path.contains("nvme") || disk_path.contains("mmcblk") {
+        format!("{}p{}", disk_path, number)
+    } else {
+        format!("{}{}", disk_path, number)
     }
 }
 
-fn flag_name(flag: &PartitionFlag) -> Option<&str> {
-    match flag {
-        PartitionFlag::Boot => Some("boot"),
-        PartitionFlag::Esp => Some("esp"),
-        PartitionFlag::Swap => Some("swap"),
-        PartitionFlag::Lvm => Some("lvm"),
-        PartitionFlag::Custom(name) => Some(name.as_str()),
+/// GPT partition type for a planned partition, following the Discoverable
+/// Partitions Specification where it covers the partition's role.
+pub fn type_guid(spec: &PartitionSpec) -> &'static str {
+    let has_flag = |wanted: fn(&PartitionFlag) -> bool| spec.flags.iter().any(wanted);
+
+    let mountpoint = spec.mountpoint.as_deref().map(normalize_mountpoint);
+
+    // `boot` on GPT is the parted-era alias for `esp`, but only a FAT
+    // partition where the ESP is mounted can be one; elsewhere it just marks
+    // the partition as bootable.
+    let boot_esp = has_flag(|flag| matches!(flag, PartitionFlag::Boot))
+        && spec.filesystem == FileSystem::Fat32
+        && matches!(mountpoint, Some("/boot/efi" | "/efi"));
+    if has_flag(|flag| matches!(flag, PartitionFlag::Esp)) || boot_esp {
+        return ESP_TYPE_GUID;
+    }
+    if spec.filesystem == FileSystem::Swap || has_flag(|flag| matches!(flag, PartitionFlag::Swap)) {
+        return SWAP_TYPE_GUID;
+    }
+    if has_flag(|flag| matches!(flag, PartitionFlag::Lvm)) {
+        return LVM_TYPE_GUID;
+    }
+
+    match mountpoint {
+        Some("/") => ROOT_ARM64_TYPE_GUID,
+        // A separate /boot next to the ESP is the extended boot loader
+        // partition.
+        Some("/boot") => XBOOTLDR_TYPE_GUID,
+        Some("/home") => HOME_TYPE_GUID,
+        Some("/srv") => SRV_TYPE_GUID,
+        _ => LINUX_FILESYSTEM_TYPE_GUID,
     }
 }
 
+/// Boundary partitions start on: 1 MiB, widened to a multiple of the
+/// physical sector and optimal I/O sizes when the device reports them.
+pub fn alignment_bytes(geometry: &DiskGeometry) -> u64 {
+    let sector = geometry
+        .physical_sector_bytes
+        .max(geometry.logical_sector_bytes)
+        .max(1);
+    let mut alignment = lcm(MIB, sector);
+
+    let optimal = geometry.optimal_io_bytes;
+    if optimal > 0 && optimal.is_multiple_of(sector) {
+        let widened = lcm(alignment, optimal);
+        if widened <= MAX_ALIGNMENT_BYTES {
+            alignment = widened;
+        }
+    }
+    alignment
+}
+
+fn lcm(a: u64, b: u64) -> u64 {
+    let (mut x, mut y) = (a, b);
+    while y != 0 {
+        (x, y) = (y, x % y);
+    }
+    a / x * b
+}
+
+/// Where a partition lies on the disk, in MiB from the start of the disk.
 #[derive(Debug, Clone)]
-struct PartitionRange {
-    start_mib: u64,
-    end_mib: u64,
+pub struct PartitionRange {
+    pub start_mib: u64,
+    /// Exclusive.
+    pub end_mib: u64,
+}
+
+/// End (exclusive) of the last sector partitions may use. The backup GPT
+/// sits behind it: the partition entry array followed by the header in the
+/// disk's last sector.
+pub fn usable_end_bytes(disk: &DiskIdentifier) -> u64 {
+    let sector = disk.geometry.logical_sector_bytes.max(1);
+    let backup_sectors = GPT_ENTRY_ARRAY_BYTES.div_ceil(sector) + 1;
+    (disk.size_bytes / sector).saturating_sub(backup_sectors) * sector
+}
+
+/// First and end (exclusive) MiB of the space `plan` lays its partitions
+/// out in. The end is rounded down to a whole MiB, which is also a whole
+/// number of sectors, so a partition running to it stops short of the
+/// backup GPT.
+pub fn available_mib(plan: &DiskPlan) -> Result<(u64, u64)> {
+    let align_mib = alignment_bytes(&plan.target.geometry) / MIB;
+    let usable_end = usable_end_bytes(&plan.target);
+    Ok(match &plan.mode {
+        DiskMode::FreeSpace(region) => {
+            if region.end_bytes > usable_end {
+                bail!("free space region extends into the backup partition table");
+            }
+            (
+                (region.start_bytes / MIB).next_multiple_of(align_mib),
+                region.end_bytes / MIB,
+            )
+        }
+        // leave room for the partition table, on an aligned boundary
+        DiskMode::UseEntireDisk | DiskMode::Custom => (align_mib, usable_end / MIB),
+    })
+}
+
+/// Space in the available area that no planned partition covers.
+pub fn unallocated_bytes(plan: &DiskPlan) -> Result<u64> {
+    let (first_mib, end_mib) = available_mib(plan)?;
+    let used_end_mib = compute_ranges(plan)?
+        .iter()
+        .map(|range| range.end_mib)
+        .max()
+        .unwrap_or(first_mib);
+    Ok(end_mib.saturating_sub(used_end_mib) * MIB)
 }
 
-fn compute_ranges(plan: &DiskPlan) -> Result<Vec<PartitionRange>> {
-    let total_mib = plan.target.size_bytes / MIB;
+pub fn compute_ranges(plan: &DiskPlan) -> Result<Vec<PartitionRange>> {
+    let align_mib = alignment_bytes(&plan.target.geometry) / MIB;
+    let (first_mib, end_mib) = available_mib(plan)?;
+    // Percentages are relative to the space being partitioned.
+    let total_mib = end_mib.saturating_sub(first_mib);
     if total_mib == 0 {
         bail!("target disk size too small to compute partition ranges");
     }
 
     let mut ranges: Vec<Option<PartitionRange>> = vec![None; plan.partitions.len()];
-    let mut cursor_mib = 1u64; // leave room for alignment
+    let mut cursor_mib = first_mib;
     let mut remainder_index = None;
 
     for (index, spec) in plan.partitions.iter().enumerate() {
@@ -164,8 +452,10 @@ fn compute_ranges(plan: &DiskPlan) -> Result<Vec<PartitionRange>> {
                 remainder_index = Some(index);
             }
             _ => {
+                // Rounding every span up keeps each following start aligned.
                 let span_mib = partition_size_to_mib(&spec.size, total_mib)
-                    .with_context(|| format!("failed to compute size for partition {}", spec.id))?;
+                    .with_context(|| format!("failed to compute size for partition {}", spec.id))?
+                    .next_multiple_of(align_mib);
                 let end_mib = cursor_mib.saturating_add(span_mib);
                 ranges[index] = Some(PartitionRange {
                     start_mib: cursor_mib,
@@ -176,23 +466,27 @@ fn compute_ranges(plan: &DiskPlan) -> Result<Vec<PartitionRange>> {
         }
     }
 
+    if cursor_mib > end_mib {
+        bail!("partitions do not fit into the available space");
+    }
+
     if let Some(idx) = remainder_index {
-        if cursor_mib >= total_mib {
+        if cursor_mib >= end_mib {
             bail!("no remaining space for remainder partition");
         }
         ranges[idx] = Some(PartitionRange {
             start_mib: cursor_mib,
-            end_mib: total_mib,
+            end_mib,
         });
-        cursor_mib = total_mib;
+        cursor_mib = end_mib;
     }
 
-    if plan.mode == DiskMode::UseEntireDisk && cursor_mib < total_mib {
+    if plan.mode == DiskMode::UseEntireDisk && cursor_mib < end_mib {
         // keep the remainder recognised but avoid creating an extra partition automatically
     }
 
     let mut final_ranges = Vec::with_capacity(plan.partitions.len());
-    for (spec, range) in plan.partitions.iter().zip(ranges.into_iter()) {
+    for (spec, range) in plan.partitions.iter().zip(ranges) {
         let range = range.ok_or_else(|| anyhow!("missing range for partition {}", spec.id))?;
         if range.end_mib <= range.start_mib {
             bail!("invalid range computed for partition {}", spec.id);
@@ -226,3 +520,223 @@ fn partition_size_to_mib(size: &PartitionSize, total_mib: u64) -> Result<u64> {
 
     Ok(span_mib)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GIB: u64 = 1024 * MIB;
+
+    fn disk(size_bytes: u64) -> DiskIdentifier {
+        DiskIdentifier {
+            path: "/dev/sda".into(),
+            size_bytes,
+            label: None,
+            geometry: DiskGeometry::default(),
+            serial: None,
+            wwn: None,
+            by_id: None,
+        }
+    }
+
+    fn spec(id: &str, mountpoint: &str, size: PartitionSize) -> PartitionSpec {
+        PartitionSpec {
+            id: id.into(),
+            mountpoint: Some(mountpoint.into()),
+            filesystem: FileSystem::Ext4,
+            size,
+            flags: Vec::new(),
+        }
+    }
+
+    fn plan_with(extra: Vec<PartitionSpec>) -> DiskPlan {
+        let disk = disk(100 * GIB);
+        let mut plan = default_plan_for_disk(&disk);
+        plan.partitions.extend(extra);
+        plan
+    }
+
+    #[test]
+    fn default_plan_is_valid() {
+        let plan = plan_with(Vec::new());
+        assert_eq!(validate_plan(&plan.target, &plan), Vec::new());
+    }
+
+    #[test]
+    fn percentages_over_100_are_reported() {
+        let mut plan = plan_with(vec![
+            spec("home", "/home", PartitionSize::Percentage(60)),
+            spec("srv", "/srv", PartitionSize::Percentage(50)),
+        ]);
+        plan.partitions.retain(|spec| spec.id != "root");
+        plan.partitions
+            .push(spec("root", "/", PartitionSize::ExactBytes(GIB)));
+
+        let errors = validate_plan(&plan.target, &plan);
+        assert!(errors.contains(&PlanError::PercentageOverflow { total: 110 }));
+    }
+
+    #[test]
+    fn partitions_larger_than_the_disk_overflow() {
+        let plan = plan_with(vec![spec(
+            "home",
+            "/home",
+            PartitionSize::ExactBytes(200 * GIB),
+        )]);
+
+        let errors = validate_plan(&plan.target, &plan);
+        assert!(errors
+            .iter()
+            .any(|error| matches!(error, PlanError::Overflow { .. })));
+    }
+
+    #[test]
+    fn check_space_gives_the_remainder_what_is_left() {
+        let plan = plan_with(Vec::new());
+        let mut errors = Vec::new();
+
+        let spans = check_space(&plan, (1, 1001), &mut errors);
+
+        assert!(errors.is_empty());
+        assert_eq!(spans, vec![Some(512), Some(488)]);
+    }
+
+    #[test]
+    fn mountpoints_are_compared_without_trailing_slashes() {
+        let plan = plan_with(vec![
+            spec("home", "/home", PartitionSize::ExactBytes(GIB)),
+            spec("home2", "/home/", PartitionSize::ExactBytes(GIB)),
+        ]);
+
+        let errors = validate_plan(&plan.target, &plan);
+        assert_eq!(
+            errors,
+            vec![PlanError::DuplicateMountpoint {
+                mountpoint: "/home".into()
+            }]
+        );
+    }
+
+    #[test]
+    fn backup_gpt_is_reserved_on_512_byte_sectors() {
+        // 32 sectors of partition entries plus the backup header, so the
+        // last usable LBA is the disk's sector count minus 34, as in sfdisk.
+        assert_eq!(usable_end_bytes(&disk(GIB)), GIB - 33 * 512);
+    }
+
+    #[test]
+    fn backup_gpt_is_reserved_on_4k_sectors() {
+        let mut disk = disk(GIB);
+        disk.geometry.logical_sector_bytes = 4096;
+        disk.geometry.physical_sector_bytes = 4096;
+
+        // 4 sectors of partition entries plus the backup header.
+        assert_eq!(usable_end_bytes(&disk), GIB - 5 * 4096);
+    }
+
+    #[test]
+    fn last_partition_stops_before_the_backup_gpt() {
+        let plan = default_plan_for_disk(&disk(GIB));
+        let ranges = compute_ranges(&plan).unwrap();
+
+        assert_eq!(ranges[1].end_mib, 1023);
+    }
+
+    #[test]
+    fn ranges_are_rounded_up_to_the_alignment() {
+        let mut disk = disk(100 * GIB);
+        disk.geometry.optimal_io_bytes = 4 * MIB;
+        let mut plan = default_plan_for_disk(&disk);
+        plan.partitions[0].size = PartitionSize::ExactBytes(301 * MIB);
+
+        let ranges = compute_ranges(&plan).unwrap();
+
+        assert_eq!((ranges[0].start_mib, ranges[0].end_mib), (4, 308));
+        assert_eq!(ranges[1].start_mib, 308);
+    }
+
+    #[test]
+    fn type_guid_follows_flags_then_mountpoint() {
+        let with =
+            |mountpoint: &str, filesystem: FileSystem, flags: &[PartitionFlag]| PartitionSpec {
+                filesystem,
+                flags: flags.to_vec(),
+                ..spec("p", mountpoint, PartitionSize::ExactBytes(GIB))
+            };
+        let cases = [
+            (
+                with(
+                    "/boot/efi",
+                    FileSystem::Fat32,
+                    &[PartitionFlag::Esp, PartitionFlag::Boot],
+                ),
+                ESP_TYPE_GUID,
+            ),
+            (
+                with("/boot/efi", FileSystem::Fat32, &[PartitionFlag::Esp]),
+                ESP_TYPE_GUID,
+            ),
+            (
+                with("/boot/efi/", FileSystem::Fat32, &[PartitionFlag::Boot]),
+                ESP_TYPE_GUID,
+            ),
+            (
+                with("/efi", FileSystem::Fat32, &[PartitionFlag::Boot]),
+                ESP_TYPE_GUID,
+            ),
+            (
+                with("/boot", FileSystem::Ext4, &[PartitionFlag::Boot]),
+                XBOOTLDR_TYPE_GUID,
+            ),
+            (
+                with("/boot", FileSystem::Fat32, &[PartitionFlag::Boot]),
+                XBOOTLDR_TYPE_GUID,
+            ),
+            (with("/boot", FileSystem::Ext4, &[]), XBOOTLDR_TYPE_GUID),
+            (
+                with("/boot/efi", FileSystem::Ext4, &[PartitionFlag::Boot]),
+                LINUX_FILESYSTEM_TYPE_GUID,
+            ),
+            (
+                with("/", FileSystem::Ext4, &[PartitionFlag::Boot]),
+                ROOT_ARM64_TYPE_GUID,
+            ),
+            (with("/", FileSystem::Btrfs, &[]), ROOT_ARM64_TYPE_GUID),
+            (with("/home/", FileSystem::Ext4, &[]), HOME_TYPE_GUID),
+            (with("/srv", FileSystem::Xfs, &[]), SRV_TYPE_GUID),
+            (
+                with("/var", FileSystem::Ext4, &[]),
+                LINUX_FILESYSTEM_TYPE_GUID,
+            ),
+            (
+                with("/data", FileSystem::Ext4, &[PartitionFlag::Swap]),
+                SWAP_TYPE_GUID,
+            ),
+            (
+                with("/data", FileSystem::Ext4, &[PartitionFlag::Lvm]),
+                LVM_TYPE_GUID,
+            ),
+            (
+                PartitionSpec {
+                    mountpoint: None,
+                    ..with("/", FileSystem::Swap, &[])
+                },
+                SWAP_TYPE_GUID,
+            ),
+        ];
+
+        let defaults = plan_with(Vec::new()).partitions;
+        assert_eq!(type_guid(&defaults[0]), ESP_TYPE_GUID);
+        assert_eq!(type_guid(&defaults[1]), ROOT_ARM64_TYPE_GUID);
+        for (spec, expected) in cases {
+            assert_eq!(
+                type_guid(&spec),
+                expected,
+                "{:?} at {:?} with {:?}",
+                spec.filesystem,
+                spec.mountpoint,
+                spec.flags
+            );
+        }
+    }
+}
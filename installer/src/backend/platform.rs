@@ -0,0 +1,280 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::backend::command::{CommandExecutor, CommandSpec};
+use crate::state::Platform;
+
+const DETECT_VIRT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Apple SoC identifiers as they appear in the device tree, with their
+/// marketing names.
+const APPLE_CHIPS: &[(&str, &str)] = &[
+    ("t8103", "M1"),
+    ("t6000", "M1 Pro"),
+    ("t6001", "M1 Max"),
+    ("t6002", "M1 Ultra"),
+    ("t8112", "M2"),
+    ("t6020", "M2 Pro"),
+    ("t6021", "M2 Max"),
+    ("t6022", "M2 Ultra"),
+];
+
+/// Raw hardware information the platform is derived from.
+#[derive(Debug, Clone, Default)]
+pub struct PlatformFacts {
+    /// Entries of `/proc/device-tree/compatible`, most specific first.
+    pub compatible: Vec<String>,
+    /// `/proc/device-tree/model`.
+    pub model: Option<String>,
+    /// Whether the kernel was booted through UEFI.
+    pub efi: bool,
+    pub dmi_vendor: Option<String>,
+    pub dmi_product: Option<String>,
+    /// Output of `systemd-detect-virt`, `None` on bare metal.
+    pub virtualization: Option<String>,
+}
+
+impl PlatformFacts {
+    /// Read the facts from the running system. Files that do not exist on
+    /// this machine (no device tree, no DMI) are simply left empty.
+    pub fn gather(executor: &dyn CommandExecutor) -> Self {
+        Self::gather_from(Path::new("/"), executor)
+    }
+
+    /// Like [`PlatformFacts::gather`] with `/proc` and `/sys` read below `root`.
+    pub fn gather_from(root: &Path, executor: &dyn CommandExecutor) -> Self {
+        let path = |relative: &str| -> PathBuf { root.join(relative) };
+
+        Self {
+            compatible: fs::read(path("proc/device-tree/compatible"))
+                .map(|raw| nul_separated(&raw))
+                .unwrap_or_default(),
+            model: fs::read(path("proc/device-tree/model"))
+                .ok()
+                .and_then(|raw| nul_separated(&raw).into_iter().next()),
+            efi: path("sys/firmware/efi").is_dir(),
+            dmi_vendor: read_trimmed(&path("sys/class/dmi/id/sys_vendor")),
+            dmi_product: read_trimmed(&path("sys/class/dmi/id/product_name")),
+            virtualization: detect_virt(executor),
+        }
+    }
+}
+
+pub fn detect(executor: &dyn CommandExecutor) -> Platform {
+    classify(&PlatformFacts::gather(executor))
+}
+
+pub fn classify(facts: &PlatformFacts) -> Platform {
+    if facts
+        .compatible
+        .iter()
+        .any(|entry| entry == "apple,arm-platform")
+    {
+        let chip = facts
+            .compatible
+            .iter()
+            .filter_map(|entry| entry.strip_prefix("apple,"))
+            .find_map(|soc| {
+                APPLE_CHIPS
+                    .iter()
+                    .find(|(id, _)| *id == soc)
+                    .map(|(id, name)| format!("{name} ({id})"))
+            })
+            .unwrap_or_else(|| "unknown".into());
+        return Platform::AppleSilicon { chip };
+    }
+
+    let qemu_virt = matches!(facts.virtualization.as_deref(), Some("qemu" | "kvm"))
+        || facts.dmi_vendor.as_deref() == Some("QEMU")
+        || facts
+            .compatible
+            .iter()
+            .any(|entry| entry == "linux,dummy-virt");
+    if qemu_virt {
+        return Platform::QemuVirt;
+    }
+
+    if facts.efi {
+        return Platform::GenericUefi {
+            vendor: facts.dmi_vendor.clone(),
+            product: facts.dmi_product.clone(),
+        };
+    }
+
+    match facts.compatible.first() {
+        Some(compatible) => Platform::Board {
+            compatible: compatible.clone(),
+            model: facts.model.clone(),
+        },
+        None => Platform::Unknown,
+    }
+}
+
+fn detect_virt(executor: &dyn CommandExecutor) -> Option<String> {
+    let spec =
+        CommandSpec::new("systemd-detect-virt", Vec::new()).with_timeout(DETECT_VIRT_TIMEOUT);
+    // Exits non-zero and prints "none" on bare metal.
+    let output = executor.run(&spec).ok()?;
+    let value = output.stdout.trim();
+    (output.success() && !value.is_empty() && value != "none").then(|| value.to_string())
+}
+
+fn nul_separated(raw: &[u8]) -> Vec<String> {
+    raw.split(|byte| *byte == 0)
+        .map(|entry| String::from_utf8_lossy(entry).trim().to_string())
+        .filter(|entry| !entry.is_empty())
+        .collect()
+}
+
+fn read_trimmed(path: &Path) -> Option<String> {
+    fs::read_to_string(path)
+        .ok()
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::scripted::{CommandMatcher, ScriptedExecutor, ScriptedResponse};
+
+    fn device_tree(compatible: &[&str], model: Option<&str>) -> PlatformFacts {
+        PlatformFacts {
+            compatible: compatible.iter().map(|entry| entry.to_string()).collect(),
+            model: model.map(Into::into),
+            ..PlatformFacts::default()
+        }
+    }
+
+    #[test]
+    fn platforms_are_classified_from_their_facts() {
+        let cases = [
+            (
+                device_tree(
+                    &["apple,j293", "apple,t8103", "apple,arm-platform"],
+                    Some("Apple MacBook Pro (13-inch, M1, 2020)"),
+                ),
+                Platform::AppleSilicon {
+                    chip: "M1 (t8103)".into(),
+                },
+            ),
+            (
+                device_tree(&["apple,j999", "apple,t9999", "apple,arm-platform"], None),
+                Platform::AppleSilicon {
+                    chip: "unknown".into(),
+                },
+            ),
+            (
+                PlatformFacts {
+                    efi: true,
+                    dmi_vendor: Some("QEMU".into()),
+                    dmi_product: Some("KVM Virtual Machine".into()),
+                    ..PlatformFacts::default()
+                },
+                Platform::QemuVirt,
+            ),
+            (
+                PlatformFacts {
+                    efi: true,
+                    virtualization: Some("kvm".into()),
+                    ..PlatformFacts::default()
+                },
+                Platform::QemuVirt,
+            ),
+            (device_tree(&["linux,dummy-virt"], None), Platform::QemuVirt),
+            (
+                PlatformFacts {
+                    efi: true,
+                    dmi_vendor: Some("Ampere".into()),
+                    dmi_product: Some("Altra".into()),
+                    virtualization: Some("vmware".into()),
+                    ..PlatformFacts::default()
+                },
+                Platform::GenericUefi {
+                    vendor: Some("Ampere".into()),
+                    product: Some("Altra".into()),
+                },
+            ),
+            (
+                PlatformFacts {
+                    efi: true,
+                    ..device_tree(&["radxa,rock-5b", "rockchip,rk3588"], None)
+                },
+                Platform::GenericUefi {
+                    vendor: None,
+                    product: None,
+                },
+            ),
+            (
+                device_tree(
+                    &["raspberrypi,4-model-b", "brcm,bcm2711"],
+                    Some("Raspberry Pi 4 Model B Rev 1.4"),
+                ),
+                Platform::Board {
+                    compatible: "raspberrypi,4-model-b".into(),
+                    model: Some("Raspberry Pi 4 Model B Rev 1.4".into()),
+                },
+            ),
+            (PlatformFacts::default(), Platform::Unknown),
+        ];
+
+        for (facts, expected) in cases {
+            assert_eq!(classify(&facts), expected, "{facts:?}");
+        }
+    }
+
+    #[test]
+    fn facts_are_read_from_device_tree_and_dmi() {
+        let root = std::env::temp_dir().join(format!("installer-platform-{}", std::process::id()));
+        fs::create_dir_all(root.join("proc/device-tree")).unwrap();
+        fs::create_dir_all(root.join("sys/firmware/efi")).unwrap();
+        fs::create_dir_all(root.join("sys/class/dmi/id")).unwrap();
+        fs::write(
+            root.join("proc/device-tree/compatible"),
+            b"apple,j293\0apple,t8103\0apple,arm-platform\0",
+        )
+        .unwrap();
+        fs::write(root.join("proc/device-tree/model"), b"Apple MacBook Pro\0").unwrap();
+        fs::write(root.join("sys/class/dmi/id/sys_vendor"), "  \n").unwrap();
+        fs::write(
+            root.join("sys/class/dmi/id/product_name"),
+            "MacBookPro17,1\n",
+        )
+        .unwrap();
+        let executor = ScriptedExecutor::new().on(
+            CommandMatcher::program("systemd-detect-virt"),
+            ScriptedResponse::exit(1).with_stdout("none\n"),
+        );
+
+        let facts = PlatformFacts::gather_from(&root, &executor);
+        let _ = fs::remove_dir_all(&root);
+
+        assert_eq!(
+            facts.compatible,
+            vec!["apple,j293", "apple,t8103", "apple,arm-platform"]
+        );
+        assert_eq!(facts.model.as_deref(), Some("Apple MacBook Pro"));
+        assert!(facts.efi);
+        assert_eq!(facts.dmi_vendor, None);
+        assert_eq!(facts.dmi_product.as_deref(), Some("MacBookPro17,1"));
+        assert_eq!(facts.virtualization, None);
+    }
+
+    #[test]
+    fn missing_files_leave_the_facts_empty() {
+        let root =
+            std::env::temp_dir().join(format!("installer-platform-empty-{}", std::process::id()));
+        let executor = ScriptedExecutor::new().on(
+            CommandMatcher::program("systemd-detect-virt"),
+            ScriptedResponse::stdout("qemu\n"),
+        );
+
+        let facts = PlatformFacts::gather_from(&root, &executor);
+
+        assert!(facts.compatible.is_empty());
+        assert!(!facts.efi);
+        assert_eq!(facts.virtualization.as_deref(), Some("qemu"));
+        assert_eq!(classify(&facts), Platform::QemuVirt);
+    }
+}
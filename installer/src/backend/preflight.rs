@@ -0,0 +1,126 @@
+use std::collections::BTreeSet;
+use std::env;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::backend::chroot::ChrootExecutor;
+use crate::backend::command::{CommandExecutor, CommandSpec};
+use crate::backend::tasks::InstallStep;
+
+const VERSION_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Tools whose version is worth reporting, with the flag that prints it.
+const VERSION_FLAGS: &[(&str, &str)] = &[
+    ("sfdisk", "--version"),
+    ("lsblk", "--version"),
+    ("mkfs.ext4", "-V"),
+    ("mkfs.btrfs", "--version"),
+    ("mkfs.xfs", "-V"),
+    ("mkswap", "--version"),
+    ("mount", "--version"),
+];
+
+#[derive(Debug, Clone)]
+pub struct ToolStatus {
+    pub program: String,
+    pub path: Option<PathBuf>,
+    pub version: Option<String>,
+}
+
+impl ToolStatus {
+    pub fn is_available(&self) -> bool {
+        self.path.is_some()
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PreflightReport {
+    pub tools: Vec<ToolStatus>,
+}
+
+impl PreflightReport {
+    pub fn missing(&self) -> Vec<&str> {
+        self.tools
+            .iter()
+            .filter(|tool| !tool.is_available())
+            .map(|tool| tool.program.as_str())
+            .collect()
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.tools.iter().all(ToolStatus::is_available)
+    }
+
+    pub fn describe(&self) -> String {
+        let missing = self.missing();
+        if missing.is_empty() {
+            format!("All {} required tools are available.", self.tools.len())
+        } else {
+            format!("Missing required tools: {}", missing.join(", "))
+        }
+    }
+}
+
+/// Every host-side program the given steps will run, sorted and without
+/// duplicates. Target-side commands contribute their chroot wrapper.
+pub fn required_programs(steps: &[InstallStep], chroot: &ChrootExecutor) -> Vec<String> {
+    steps
+        .iter()
+        .flat_map(|step| step.host_commands(chroot))
+        .map(|spec| spec.program)
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect()
+}
+
+/// Look up each program on `PATH` and, where useful, ask it for its version.
+pub fn check_tools(programs: &[String], executor: &dyn CommandExecutor) -> PreflightReport {
+    let tools = programs
+        .iter()
+        .map(|program| {
+            let path = find_on_path(program);
+            let version = path.as_ref().and_then(|_| tool_version(executor, program));
+            ToolStatus {
+                program: program.clone(),
+                path,
+                version,
+            }
+        })
+        .collect();
+
+    PreflightReport { tools }
+}
+
+pub fn find_on_path(program: &str) -> Option<PathBuf> {
+    if program.contains('/') {
+        let path = PathBuf::from(program);
+        return is_executable(&path).then_some(path);
+    }
+
+    let search_path = env::var_os("PATH")?;
+    env::split_paths(&search_path)
+        .map(|dir| dir.join(program))
+        .find(|candidate| is_executable(candidate))
+}
+
+fn is_executable(path: &Path) -> bool {
+    path.metadata()
+        .map(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+        .unwrap_or(false)
+}
+
+fn tool_version(executor: &dyn CommandExecutor, program: &str) -> Option<String> {
+    let (_, flag) = VERSION_FLAGS.iter().find(|(name, _)| *name == program)?;
+    let spec = CommandSpec::new(program, vec![flag.to_string()]).with_timeout(VERSION_TIMEOUT);
+    let output = executor.run(&spec).ok()?;
+
+    // Some tools (mkfs.ext4 -V) print their version on stderr.
+    output
+        .stdout
+        .lines()
+        .chain(output.stderr.lines())
+        .map(str::trim)
+        .find(|line| !line.is_empty())
+        .map(str::to_string)
+}
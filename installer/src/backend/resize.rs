@@ -0,0 +1,390 @@
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+
+use crate::backend::command::CommandSpec;
+use crate::backend::{disk, partition};
+use crate::state::{BlockNode, DiskDevice, FreeSpaceRegion, PartitionResize, ResizableFilesystem};
+
+const MIB: u64 = 1024 * 1024;
+
+/// btrfs can only be shrunk while mounted; it is mounted here for that.
+pub const RESIZE_MOUNT_DIR: &str = "/run/arm-installer/resize";
+
+/// Smallest size a partition may be shrunk to.
+pub const MIN_SHRUNK_BYTES: u64 = 1024 * MIB;
+
+const CHECK_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+const RESIZE_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+const SFDISK_TIMEOUT: Duration = Duration::from_secs(120);
+const MOUNT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Partitions on `disk` that can be shrunk: unmounted ext4, btrfs and NTFS
+/// partitions on a GPT disk.
+pub fn shrink_candidates(disk: &DiskDevice) -> Vec<&BlockNode> {
+    if disk.partition_table.as_deref() != Some("gpt") {
+        return Vec::new();
+    }
+    disk.partitions
+        .iter()
+        .filter(|node| {
+            node.kind == "part"
+                && node.mountpoints.is_empty()
+                && node.start_bytes.is_some()
+                && node
+                    .filesystem
+                    .as_deref()
+                    .and_then(ResizableFilesystem::from_fstype)
+                    .is_some()
+        })
+        .collect()
+}
+
+/// Plan shrinking the partition at `path` to `new_bytes` (rounded down to a
+/// MiB), returning the resize and the gap it leaves for the install. The gap
+/// also takes in any unallocated space already following the partition.
+pub fn plan_shrink(
+    disk: &DiskDevice,
+    path: &str,
+    new_bytes: u64,
+) -> Result<(PartitionResize, FreeSpaceRegion)> {
+    let node = shrink_candidates(disk)
+        .into_iter()
+        .find(|node| node.path == path)
+        .with_context(|| format!("{path} is not a partition that can be shrunk"))?;
+    let filesystem = node
+        .filesystem
+        .as_deref()
+        .and_then(ResizableFilesystem::from_fstype)
+        .with_context(|| format!("{path} has no resizable filesystem"))?;
+    let start_bytes = node
+        .start_bytes
+        .with_context(|| format!("start of {path} is unknown"))?;
+    let number = disk::partition_number(path)
+        .with_context(|| format!("cannot tell the partition number of {path}"))?;
+
+    let new_bytes = new_bytes / MIB * MIB;
+    if new_bytes < MIN_SHRUNK_BYTES {
+        bail!(
+            "{path} cannot be shrunk below {} MiB",
+            MIN_SHRUNK_BYTES / MIB
+        );
+    }
+    if new_bytes >= node.size_bytes {
+        bail!("the new size of {path} must be smaller than its current size");
+    }
+
+    let old_end = start_bytes + node.size_bytes;
+    let next_start = disk
+        .partitions
+        .iter()
+        .filter_map(|other| other.start_bytes)
+        .filter(|start| *start >= old_end)
+        .min();
+    let end_bytes =
+        next_start.unwrap_or_else(|| partition::usable_end_bytes(&disk.identifier)) / MIB * MIB;
+
+    let region = FreeSpaceRegion {
+        start_bytes: (start_bytes + new_bytes).next_multiple_of(MIB),
+        end_bytes,
+        used_numbers: disk
+            .partitions
+            .iter()
+            .filter_map(|other| disk::partition_number(&other.path))
+            .collect(),
+    };
+    if region.size_bytes() < disk::MIN_FREE_REGION_BYTES {
+        bail!("shrinking {path} to that size does not free enough space");
+    }
+
+    let resize = PartitionResize {
+        path: path.to_string(),
+        number,
+        filesystem,
+        start_bytes,
+        current_bytes: node.size_bytes,
+        new_bytes,
+    };
+    Ok((resize, region))
+}
+
+/// Commands that shrink `resize` on `disk_path`: check the filesystem,
+/// shrink it, then shrink the partition around it. Each step stops the
+/// install on failure, so a filesystem that cannot be shrunk leaves the
+/// partition table untouched.
+pub fn shrink_commands(
+    resize: &PartitionResize,
+    disk_path: &str,
+    sector_bytes: u64,
+) -> Result<Vec<CommandSpec>> {
+    if sector_bytes == 0 || !resize.new_bytes.is_multiple_of(sector_bytes) {
+        bail!(
+            "new size of {} is not a whole number of {sector_bytes}-byte sectors",
+            resize.path
+        );
+    }
+
+    let device = resize.path.clone();
+    let size = resize.new_bytes;
+    let mut commands = vec![check_command(resize)];
+    match resize.filesystem {
+        ResizableFilesystem::Ext4 => commands.push(
+            CommandSpec::new("resize2fs", vec![device, format!("{}K", size / 1024)])
+                .with_timeout(RESIZE_TIMEOUT),
+        ),
+        ResizableFilesystem::Btrfs => commands.extend([
+            CommandSpec::new("mkdir", vec!["-p".into(), RESIZE_MOUNT_DIR.into()]),
+            CommandSpec::new(
+                "mount",
+                vec!["-t".into(), "btrfs".into(), device, RESIZE_MOUNT_DIR.into()],
+            )
+            .with_timeout(MOUNT_TIMEOUT),
+            // A failed resize must not leave the filesystem mounted.
+            CommandSpec::new(
+                "btrfs",
+                vec![
+                    "filesystem".into(),
+                    "resize".into(),
+                    size.to_string(),
+                    RESIZE_MOUNT_DIR.into(),
+                ],
+            )
+            .with_timeout(RESIZE_TIMEOUT)
+            .on_failure(unmount_command()),
+            unmount_command(),
+        ]),
+        // ntfsresize asks for confirmation before writing.
+        ResizableFilesystem::Ntfs => commands.push(
+            CommandSpec::new(
+                "ntfsresize",
+                vec!["--size".into(), size.to_string(), device],
+            )
+            .stdin("y\n")
+            .with_timeout(RESIZE_TIMEOUT),
+        ),
+    }
+
+    // Keep the start and only move the end of the partition.
+    commands.push(
+        CommandSpec::new(
+            "sfdisk",
+            vec![
+                "-N".into(),
+                resize.number.to_string(),
+                disk_path.to_string(),
+            ],
+        )
+        .stdin(format!(",{}\n", size / sector_bytes))
+        .with_timeout(SFDISK_TIMEOUT),
+    );
+    Ok(commands)
+}
+
+fn unmount_command() -> CommandSpec {
+    CommandSpec::new("umount", vec![RESIZE_MOUNT_DIR.into()]).with_timeout(MOUNT_TIMEOUT)
+}
+
+fn check_command(resize: &PartitionResize) -> CommandSpec {
+    let device = resize.path.clone();
+    let spec = match resize.filesystem {
+        // resize2fs refuses to shrink a filesystem that was not just checked.
+        // Exit code 1 means e2fsck repaired something and the filesystem is
+        // now clean; 2 and above need a reboot or manual attention.
+        ResizableFilesystem::Ext4 => {
+            CommandSpec::new("e2fsck", vec!["-f".into(), "-p".into(), device])
+                .with_success_codes([1])
+        }
+        ResizableFilesystem::Btrfs => {
+            CommandSpec::new("btrfs", vec!["check".into(), "--readonly".into(), device])
+        }
+        ResizableFilesystem::Ntfs => CommandSpec::new("ntfsresize", vec!["--check".into(), device]),
+    };
+    spec.with_timeout(CHECK_TIMEOUT)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::ExitStatus;
+
+    use super::*;
+    use crate::state::{DiskGeometry, DiskIdentifier};
+
+    const GIB: u64 = 1024 * MIB;
+
+    fn partition(path: &str, fstype: &str, start_bytes: u64, size_bytes: u64) -> BlockNode {
+        BlockNode {
+            name: path.trim_start_matches("/dev/").into(),
+            path: path.into(),
+            kind: "part".into(),
+            size_bytes,
+            filesystem: Some(fstype.into()),
+            label: None,
+            partlabel: None,
+            parttype: None,
+            uuid: None,
+            partuuid: None,
+            mountpoints: Vec::new(),
+            read_only: false,
+            start_bytes: Some(start_bytes),
+            children: Vec::new(),
+        }
+    }
+
+    fn disk() -> DiskDevice {
+        DiskDevice {
+            identifier: DiskIdentifier {
+                path: "/dev/sda".into(),
+                size_bytes: 100 * GIB,
+                label: None,
+                geometry: DiskGeometry::default(),
+                serial: None,
+                wwn: None,
+                by_id: None,
+            },
+            transport: None,
+            read_only: false,
+            removable: false,
+            partition_table: Some("gpt".into()),
+            filesystem: None,
+            mountpoints: Vec::new(),
+            partitions: vec![
+                partition("/dev/sda1", "vfat", MIB, 512 * MIB),
+                partition("/dev/sda2", "ext4", 513 * MIB, 60 * GIB),
+            ],
+            ineligible: None,
+            existing_systems: Vec::new(),
+            free_regions: Vec::new(),
+        }
+    }
+
+    fn resize(filesystem: ResizableFilesystem) -> PartitionResize {
+        PartitionResize {
+            path: "/dev/sda2".into(),
+            number: 2,
+            filesystem,
+            start_bytes: 513 * MIB,
+            current_bytes: 60 * GIB,
+            new_bytes: 20 * GIB,
+        }
+    }
+
+    fn programs(commands: &[CommandSpec]) -> Vec<String> {
+        commands
+            .iter()
+            .map(|spec| format!("{} {}", spec.program, spec.args.join(" ")))
+            .collect()
+    }
+
+    #[test]
+    fn shrink_leaves_a_gap_after_the_partition() {
+        let (resize, region) = plan_shrink(&disk(), "/dev/sda2", 20 * GIB + 123).unwrap();
+
+        assert_eq!(resize.new_bytes, 20 * GIB);
+        assert_eq!(resize.number, 2);
+        assert_eq!(region.start_bytes, 513 * MIB + 20 * GIB);
+        assert_eq!(region.used_numbers, vec![1, 2]);
+    }
+
+    #[test]
+    fn shrink_below_the_minimum_is_rejected() {
+        let err = plan_shrink(&disk(), "/dev/sda2", MIN_SHRUNK_BYTES - MIB).unwrap_err();
+        assert!(err.to_string().contains("cannot be shrunk below"));
+    }
+
+    #[test]
+    fn shrink_must_make_the_partition_smaller() {
+        assert!(plan_shrink(&disk(), "/dev/sda2", 60 * GIB).is_err());
+        assert!(plan_shrink(&disk(), "/dev/sda1", 256 * MIB).is_err());
+    }
+
+    #[test]
+    fn sizes_off_the_sector_grid_are_rejected() {
+        let mut resize = resize(ResizableFilesystem::Ext4);
+        resize.new_bytes += 512;
+
+        assert!(shrink_commands(&resize, "/dev/sda", 4096).is_err());
+        assert!(shrink_commands(&resize, "/dev/sda", 0).is_err());
+    }
+
+    #[test]
+    fn ext4_is_checked_then_resized() {
+        let commands =
+            shrink_commands(&resize(ResizableFilesystem::Ext4), "/dev/sda", 512).unwrap();
+
+        assert_eq!(
+            programs(&commands),
+            vec![
+                "e2fsck -f -p /dev/sda2",
+                "resize2fs /dev/sda2 20971520K",
+                "sfdisk -N 2 /dev/sda",
+            ]
+        );
+    }
+
+    #[test]
+    fn e2fsck_repairs_count_as_success() {
+        let commands =
+            shrink_commands(&resize(ResizableFilesystem::Ext4), "/dev/sda", 512).unwrap();
+        let e2fsck = &commands[0];
+        let exit = |code: i32| ExitStatus::from_raw(code << 8);
+
+        assert!(e2fsck.accepts(&exit(0)));
+        assert!(e2fsck.accepts(&exit(1)));
+        assert!(!e2fsck.accepts(&exit(2)));
+        assert!(!e2fsck.accepts(&exit(4)));
+    }
+
+    #[test]
+    fn btrfs_is_resized_while_mounted_and_unmounted_on_failure() {
+        let commands =
+            shrink_commands(&resize(ResizableFilesystem::Btrfs), "/dev/sda", 512).unwrap();
+
+        assert_eq!(
+            programs(&commands),
+            vec![
+                "btrfs check --readonly /dev/sda2".to_string(),
+                format!("mkdir -p {RESIZE_MOUNT_DIR}"),
+                format!("mount -t btrfs /dev/sda2 {RESIZE_MOUNT_DIR}"),
+                format!("btrfs filesystem resize 21474836480 {RESIZE_MOUNT_DIR}"),
+                format!("umount {RESIZE_MOUNT_DIR}"),
+                "sfdisk -N 2 /dev/sda".to_string(),
+            ]
+        );
+        assert_eq!(
+            programs(&commands[3].on_failure),
+            vec![format!("umount {RESIZE_MOUNT_DIR}")]
+        );
+        assert!(commands[2].timeout.is_some());
+        assert!(commands[4].timeout.is_some());
+    }
+
+    #[test]
+    fn ntfs_resize_is_confirmed_on_stdin() {
+        let commands =
+            shrink_commands(&resize(ResizableFilesystem::Ntfs), "/dev/sda", 512).unwrap();
+
+        assert_eq!(
+            programs(&commands),
+            vec![
+                "ntfsresize --check /dev/sda2",
+                "ntfsresize --size 21474836480 /dev/sda2",
+                "sfdisk -N 2 /dev/sda",
+            ]
+        );
+        assert_eq!(commands[1].stdin.as_ref().unwrap().as_bytes(), b"y\n");
+    }
+
+    #[test]
+    fn sfdisk_gets_the_new_size_in_sectors() {
+        let commands =
+            shrink_commands(&resize(ResizableFilesystem::Ext4), "/dev/sda", 4096).unwrap();
+        let sfdisk = commands.last().unwrap();
+
+        let expected = format!(",{}\n", 20 * GIB / 4096);
+        assert_eq!(
+            sfdisk.stdin.as_ref().unwrap().as_bytes(),
+            expected.as_bytes()
+        );
+    }
+}
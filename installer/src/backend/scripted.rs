@@ -0,0 +1,231 @@
+use std::os::unix::process::ExitStatusExt;
+use std::process::ExitStatus;
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, Result};
+use async_trait::async_trait;
+use parking_lot::Mutex;
+
+use crate::backend::command::{CancellationToken, CommandExecutor, CommandOutput, CommandSpec};
+use crate::backend::concurrent::AsyncCommandExecutor;
+
+/// A fake [`CommandExecutor`] that answers commands from a list of scripted
+/// rules instead of spawning processes. Every call is recorded so that the
+/// sequence of executed commands can be inspected afterwards.
+#[derive(Debug, Default)]
+pub struct ScriptedExecutor {
+    rules: Mutex<Vec<ScriptedRule>>,
+    calls: Mutex<Vec<CommandSpec>>,
+    fallback: Option<ScriptedResponse>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ScriptedRule {
+    pub matcher: CommandMatcher,
+    pub response: ScriptedResponse,
+    /// Number of times the rule may still match; `None` means unlimited.
+    pub remaining: Option<usize>,
+}
+
+#[derive(Debug, Clone)]
+pub struct CommandMatcher {
+    pub program: String,
+    pub args: ArgsMatcher,
+}
+
+#[derive(Debug, Clone)]
+pub enum ArgsMatcher {
+    Any,
+    Exact(Vec<String>),
+    Contains(Vec<String>),
+}
+
+#[derive(Debug, Clone)]
+pub struct ScriptedResponse {
+    pub stdout: String,
+    pub stderr: String,
+    pub exit_code: i32,
+}
+
+impl CommandMatcher {
+    pub fn program(program: impl Into<String>) -> Self {
+        Self {
+            program: program.into(),
+            args: ArgsMatcher::Any,
+        }
+    }
+
+    pub fn with_exact_args(mut self, args: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.args = ArgsMatcher::Exact(args.into_iter().map(Into::into).collect());
+        self
+    }
+
+    pub fn with_args_containing(
+        mut self,
+        args: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Self {
+        self.args = ArgsMatcher::Contains(args.into_iter().map(Into::into).collect());
+        self
+    }
+
+    pub fn matches(&self, spec: &CommandSpec) -> bool {
+        if self.program != spec.program {
+            return false;
+        }
+
+        match &self.args {
+            ArgsMatcher::Any => true,
+            ArgsMatcher::Exact(expected) => expected == &spec.args,
+            ArgsMatcher::Contains(expected) => expected.iter().all(|arg| spec.args.contains(arg)),
+        }
+    }
+}
+
+impl ScriptedResponse {
+    pub fn success() -> Self {
+        Self::exit(0)
+    }
+
+    pub fn exit(code: i32) -> Self {
+        Self {
+            stdout: String::new(),
+            stderr: String::new(),
+            exit_code: code,
+        }
+    }
+
+    pub fn stdout(value: impl Into<String>) -> Self {
+        Self::success().with_stdout(value)
+    }
+
+    pub fn with_stdout(mut self, value: impl Into<String>) -> Self {
+        self.stdout = value.into();
+        self
+    }
+
+    pub fn with_stderr(mut self, value: impl Into<String>) -> Self {
+        self.stderr = value.into();
+        self
+    }
+
+    fn to_output(&self, spec: &CommandSpec) -> CommandOutput {
+        CommandOutput::new(
+            spec,
+            self.stdout.clone(),
+            self.stderr.clone(),
+            exit_status(self.exit_code),
+        )
+    }
+}
+
+impl ScriptedExecutor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Answer every matching command with `response`.
+    pub fn on(self, matcher: CommandMatcher, response: ScriptedResponse) -> Self {
+        self.push_rule(matcher, response, None)
+    }
+
+    /// Answer only the next `times` matching commands with `response`; later
+    /// calls fall through to the following rules.
+    pub fn on_times(
+        self,
+        matcher: CommandMatcher,
+        response: ScriptedResponse,
+        times: usize,
+    ) -> Self {
+        self.push_rule(matcher, response, Some(times))
+    }
+
+    /// Response for commands that match no rule. Without a fallback an
+    /// unmatched command is reported as an execution error.
+    pub fn with_fallback(mut self, response: ScriptedResponse) -> Self {
+        self.fallback = Some(response);
+        self
+    }
+
+    pub fn calls(&self) -> Vec<CommandSpec> {
+        self.calls.lock().clone()
+    }
+
+    pub fn call_count(&self, program: &str) -> usize {
+        self.calls
+            .lock()
+            .iter()
+            .filter(|spec| spec.program == program)
+            .count()
+    }
+
+    fn push_rule(
+        self,
+        matcher: CommandMatcher,
+        response: ScriptedResponse,
+        remaining: Option<usize>,
+    ) -> Self {
+        self.rules.lock().push(ScriptedRule {
+            matcher,
+            response,
+            remaining,
+        });
+        self
+    }
+
+    fn next_response(&self, spec: &CommandSpec) -> Option<ScriptedResponse> {
+        let mut rules = self.rules.lock();
+        let rule = rules
+            .iter_mut()
+            .find(|rule| rule.remaining != Some(0) && rule.matcher.matches(spec))?;
+
+        if let Some(remaining) = rule.remaining.as_mut() {
+            *remaining -= 1;
+        }
+
+        Some(rule.response.clone())
+    }
+}
+
+impl CommandExecutor for ScriptedExecutor {
+    fn run(&self, spec: &CommandSpec) -> Result<CommandOutput> {
+        self.calls.lock().push(spec.clone());
+
+        match self.next_response(spec).or_else(|| self.fallback.clone()) {
+            Some(response) => Ok(response.to_output(spec)),
+            None => bail!(
+                "no scripted response for {} {}",
+                spec.program,
+                spec.args.join(" ")
+            ),
+        }
+    }
+}
+
+/// Runs a blocking [`CommandExecutor`] on tokio's blocking pool, so fakes
+/// can take part in concurrent runs.
+pub struct BlockingExecutor {
+    inner: Arc<dyn CommandExecutor + Send + Sync>,
+}
+
+impl BlockingExecutor {
+    pub fn new(inner: Arc<dyn CommandExecutor + Send + Sync>) -> Self {
+        Self { inner }
+    }
+}
+
+#[async_trait]
+impl AsyncCommandExecutor for BlockingExecutor {
+    async fn run(&self, spec: &CommandSpec, cancel: &CancellationToken) -> Result<CommandOutput> {
+        let inner = Arc::clone(&self.inner);
+        let spec = spec.clone();
+        let cancel = cancel.clone();
+        tokio::task::spawn_blocking(move || inner.run_streaming(&spec, &cancel, &mut |_, _| {}))
+            .await
+            .map_err(|err| anyhow!("command task failed: {err}"))?
+    }
+}
+
+fn exit_status(code: i32) -> ExitStatus {
+    // Wait status encoding: the exit code lives in the second byte.
+    ExitStatus::from_raw((code & 0xff) << 8)
+}
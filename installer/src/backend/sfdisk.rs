@@ -0,0 +1,192 @@
+use std::fmt::Write as _;
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+
+use crate::backend::command::CommandSpec;
+use crate::backend::partition::{self, PartitionRange};
+use crate::state::{DiskMode, DiskPlan, PartitionSpec};
+
+const MIB: u64 = 1024 * 1024;
+const SFDISK_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Render the whole plan as an sfdisk script. Whole-disk plans describe a
+/// fresh GPT; free-space plans only list the new partitions and are applied
+/// with `--append`.
+pub fn render_script(plan: &DiskPlan) -> Result<String> {
+    let sector = plan.target.geometry.logical_sector_bytes;
+    if sector == 0 || !MIB.is_multiple_of(sector) {
+        bail!(
+            "unsupported logical sector size {sector} on {}",
+            plan.target.path
+        );
+    }
+
+    let ranges = partition::compute_ranges(plan)?;
+    let numbers = partition::partition_numbers(plan);
+
+    let mut script = String::new();
+    if !is_append(plan) {
+        script.push_str("label: gpt\n");
+        script.push_str("unit: sectors\n");
+        let _ = writeln!(script, "sector-size: {sector}");
+        script.push('\n');
+    }
+
+    for ((spec, range), number) in plan.partitions.iter().zip(&ranges).zip(numbers) {
+        let node = partition::partition_device_path(&plan.target.path, number);
+        let _ = writeln!(script, "{node} : {}", partition_line(spec, range, sector)?);
+    }
+
+    Ok(script)
+}
+
+/// The sfdisk call that writes `plan` to the disk in one go.
+pub fn apply_command(plan: &DiskPlan) -> Result<CommandSpec> {
+    build_command(plan, false)
+}
+
+/// Like [`apply_command`] with `--no-act`: sfdisk reads the device and
+/// validates the script but writes nothing.
+pub fn dry_run_command(plan: &DiskPlan) -> Result<CommandSpec> {
+    build_command(plan, true)
+}
+
+fn build_command(plan: &DiskPlan, dry_run: bool) -> Result<CommandSpec> {
+    let script = render_script(plan)?;
+
+    let mut args: Vec<String> = Vec::new();
+    if is_append(plan) {
+        args.push("--append".into());
+    } else {
+        // Remove stale filesystem and RAID signatures from the old layout.
+        args.extend(["--wipe".into(), "always".into()]);
+    }
+    args.extend(["--wipe-partitions".into(), "always".into()]);
+    if dry_run {
+        args.push("--no-act".into());
+    }
+    args.push(plan.target.path.clone());
+
+    Ok(CommandSpec::new("sfdisk", args)
+        .stdin(script)
+        .with_timeout(SFDISK_TIMEOUT))
+}
+
+fn is_append(plan: &DiskPlan) -> bool {
+    matches!(plan.mode, DiskMode::FreeSpace(_))
+}
+
+fn partition_line(spec: &PartitionSpec, range: &PartitionRange, sector: u64) -> Result<String> {
+    if spec.id.contains('"') {
+        bail!("partition name {:?} must not contain quotes", spec.id);
+    }
+
+    let sectors_per_mib = MIB / sector;
+    Ok(format!(
+        "start={}, size={}, type={}, name=\"{}\"",
+        range.start_mib * sectors_per_mib,
+        (range.end_mib - range.start_mib) * sectors_per_mib,
+        partition::type_guid(spec),
+        spec.id
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::{DiskGeometry, DiskIdentifier, FreeSpaceRegion};
+
+    const GIB: u64 = 1024 * MIB;
+
+    fn disk(logical_sector_bytes: u64) -> DiskIdentifier {
+        DiskIdentifier {
+            path: "/dev/sda".into(),
+            size_bytes: 64 * GIB,
+            label: None,
+            geometry: DiskGeometry {
+                logical_sector_bytes,
+                physical_sector_bytes: logical_sector_bytes,
+                ..DiskGeometry::default()
+            },
+            serial: None,
+            wwn: None,
+            by_id: None,
+        }
+    }
+
+    #[test]
+    fn whole_disk_script_describes_a_fresh_gpt() {
+        let plan = partition::default_plan_for_disk(&disk(512));
+
+        assert_eq!(
+            render_script(&plan).unwrap(),
+            "label: gpt\n\
+             unit: sectors\n\
+             sector-size: 512\n\
+             \n\
+             /dev/sda1 : start=2048, size=1048576, \
+             type=C12A7328-F81F-11D2-BA4B-00A0C93EC93B, name=\"esp\"\n\
+             /dev/sda2 : start=1050624, size=133165056, \
+             type=B921B045-1DF0-41C3-AF44-4C6F280D3FAE, name=\"root\"\n"
+        );
+        assert_eq!(
+            dry_run_command(&plan).unwrap().args,
+            vec![
+                "--wipe",
+                "always",
+                "--wipe-partitions",
+                "always",
+                "--no-act",
+                "/dev/sda"
+            ]
+        );
+    }
+
+    #[test]
+    fn free_space_script_only_appends_the_new_partitions() {
+        let region = FreeSpaceRegion {
+            start_bytes: 20 * GIB,
+            end_bytes: 40 * GIB,
+            used_numbers: vec![1, 2],
+        };
+        let plan = partition::plan_for_free_space(&disk(512), &region);
+
+        assert_eq!(
+            render_script(&plan).unwrap(),
+            "/dev/sda3 : start=41943040, size=1048576, \
+             type=C12A7328-F81F-11D2-BA4B-00A0C93EC93B, name=\"esp\"\n\
+             /dev/sda4 : start=42991616, size=40894464, \
+             type=B921B045-1DF0-41C3-AF44-4C6F280D3FAE, name=\"root\"\n"
+        );
+        let command = apply_command(&plan).unwrap();
+        assert_eq!(
+            command.args,
+            vec!["--append", "--wipe-partitions", "always", "/dev/sda"]
+        );
+    }
+
+    #[test]
+    fn script_counts_in_logical_sectors() {
+        let plan = partition::default_plan_for_disk(&disk(4096));
+
+        let script = render_script(&plan).unwrap();
+
+        assert!(script.starts_with("label: gpt\nunit: sectors\nsector-size: 4096\n\n"));
+        assert!(script.contains("/dev/sda1 : start=256, size=131072, "));
+    }
+
+    #[test]
+    fn unsupported_sector_sizes_are_rejected() {
+        assert!(render_script(&partition::default_plan_for_disk(&disk(0))).is_err());
+        assert!(render_script(&partition::default_plan_for_disk(&disk(3000))).is_err());
+    }
+
+    #[test]
+    fn quotes_in_partition_names_are_rejected() {
+        let mut plan = partition::default_plan_for_disk(&disk(512));
+        plan.partitions[1].id = "my \"root\"".into();
+
+        assert!(render_script(&plan).is_err());
+    }
+}
@@ -1,43 +1,214 @@
+use std::fmt;
+
 use anyhow::Result;
 
-use crate::backend::command::CommandSpec;
-use crate::backend::{filesystem, packages, partition};
-use crate::state::{FileSystem, InstallerState, PartitionSpec};
+use crate::backend::chroot::ChrootExecutor;
+use crate::backend::command::{CommandSpec, StdinPayload};
+use crate::backend::{filesystem, packages, partition, resize, sfdisk};
+use crate::state::{
+    DiscardPolicy, DiskIdentifier, DiskPlan, FileSystem, InstallerState, PartitionSpec,
+};
 
 const TARGET_ROOT: &str = "/mnt/arm-distro";
 
 #[derive(Debug, Clone)]
 pub struct InstallPlan {
+    target_root: String,
     steps: Vec<InstallStep>,
 }
 
 impl InstallPlan {
-    pub fn new(steps: Vec<InstallStep>) -> Self {
-        Self { steps }
+    pub fn new(target_root: impl Into<String>, steps: Vec<InstallStep>) -> Self {
+        Self {
+            target_root: target_root.into(),
+            steps,
+        }
+    }
+
+    /// Host path the installed system is mounted under. Paths in file and
+    /// chroot actions are relative to it.
+    pub fn target_root(&self) -> &str {
+        &self.target_root
     }
 
     pub fn steps(&self) -> &[InstallStep] {
         &self.steps
     }
 
-    pub fn is_empty(&self) -> bool {
-        self.steps.is_empty()
+    /// Point the plan, built for a disk that was at `old_path`, at the same
+    /// disk under its current name. Every argument and sfdisk script line
+    /// naming the disk or one of its partitions is rewritten.
+    pub fn retarget(&mut self, old_path: &str, disk: &DiskIdentifier) {
+        for action in self.steps.iter_mut().flat_map(|step| &mut step.actions) {
+            match action {
+                PlanAction::Run(spec) | PlanAction::RunInChroot(spec) => {
+                    retarget_command(spec, old_path, &disk.path)
+                }
+                PlanAction::RunConcurrently(specs) => {
+                    for spec in specs {
+                        retarget_command(spec, old_path, &disk.path);
+                    }
+                }
+                PlanAction::VerifyTarget(target) if target.path == old_path => {
+                    *target = disk.clone();
+                }
+                _ => {}
+            }
+        }
     }
 }
 
+fn retarget_command(spec: &mut CommandSpec, old_path: &str, new_path: &str) {
+    for arg in &mut spec.args {
+        if let Some(renamed) = rename_device(arg, old_path, new_path) {
+            *arg = renamed;
+        }
+    }
+    let script = spec
+        .stdin
+        .as_ref()
+        .and_then(|stdin| std::str::from_utf8(stdin.as_bytes()).ok());
+    if let Some(script) = script {
+        let rewritten = script
+            .split('\n')
+            .map(|line| {
+                line.split(' ')
+                    .map(|word| rename_device(word, old_path, new_path).unwrap_or(word.into()))
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+        spec.stdin = Some(StdinPayload::new(rewritten));
+    }
+    for cleanup in &mut spec.on_failure {
+        retarget_command(cleanup, old_path, new_path);
+    }
+}
+
+/// `word` with the disk at `old_path` renamed to `new_path`, if it names
+/// that disk or one of its partitions.
+fn rename_device(word: &str, old_path: &str, new_path: &str) -> Option<String> {
+    if word == old_path {
+        return Some(new_path.to_string());
+    }
+    let suffix = word.strip_prefix(old_path)?;
+    let number: u32 = suffix.trim_start_matches('p').parse().ok()?;
+    (partition::partition_device_path(old_path, number) == word)
+        .then(|| partition::partition_device_path(new_path, number))
+}
+
 #[derive(Debug, Clone)]
 pub struct InstallStep {
     pub stage: InstallStage,
     pub summary: String,
-    pub commands: Vec<CommandSpec>,
+    pub actions: Vec<PlanAction>,
 }
 
 impl InstallStep {
-    pub fn new(stage: InstallStage, summary: impl Into<String>, commands: Vec<CommandSpec>) -> Self {
+    pub fn new(stage: InstallStage, summary: impl Into<String>, actions: Vec<PlanAction>) -> Self {
         Self {
             stage,
             summary: summary.into(),
-            commands,
+            actions,
+        }
+    }
+
+    /// Commands this step runs on the host, with target-side commands
+    /// wrapped for `chroot`.
+    pub fn host_commands(&self, chroot: &ChrootExecutor) -> Vec<CommandSpec> {
+        self.actions
+            .iter()
+            .flat_map(|action| match action {
+                PlanAction::Run(spec) => vec![spec.clone()],
+                PlanAction::RunConcurrently(specs) => specs.clone(),
+                PlanAction::RunInChroot(spec) => vec![chroot.wrap(spec)],
+                PlanAction::WriteFile { .. }
+                | PlanAction::Symlink { .. }
+                | PlanAction::CheckTools
+                | PlanAction::VerifyTarget(_)
+                | PlanAction::SaveTranscript => Vec::new(),
+            })
+            .collect()
+    }
+}
+
+/// A single unit of work in an install step. File and symlink actions are
+/// carried out by the backend itself; paths are relative to the target root.
+#[derive(Debug, Clone)]
+pub enum PlanAction {
+    /// Run a command on the live system.
+    Run(CommandSpec),
+    /// Run independent commands on the live system at the same time. Unlike
+    /// [`PlanAction::Run`], their `retry` policies and `on_failure` cleanups
+    /// are ignored and output reaches the log only once each command has
+    /// exited.
+    RunConcurrently(Vec<CommandSpec>),
+    /// Run a command inside the installed system.
+    RunInChroot(CommandSpec),
+    WriteFile {
+        path: String,
+        contents: String,
+        mode: u32,
+    },
+    /// Create (or replace) `link` so that it points at `target`.
+    Symlink { target: String, link: String },
+    /// Fail unless every host program the plan runs is available.
+    CheckTools,
+    /// Find the selected disk by its stable identity and re-target the rest
+    /// of the plan if its kernel name changed. Fails when the disk is gone or
+    /// cannot be told apart from another one.
+    VerifyTarget(DiskIdentifier),
+    /// Copy the command transcript into the installed system.
+    SaveTranscript,
+}
+
+impl PlanAction {
+    pub fn write_file(path: impl Into<String>, contents: impl Into<String>) -> Self {
+        PlanAction::WriteFile {
+            path: path.into(),
+            contents: contents.into(),
+            mode: 0o644,
+        }
+    }
+
+    pub fn symlink(target: impl Into<String>, link: impl Into<String>) -> Self {
+        PlanAction::Symlink {
+            target: target.into(),
+            link: link.into(),
+        }
+    }
+
+    pub fn in_chroot(program: impl Into<String>, args: impl Into<Vec<String>>) -> Self {
+        PlanAction::RunInChroot(CommandSpec::new(program, args))
+    }
+}
+
+impl From<CommandSpec> for PlanAction {
+    fn from(spec: CommandSpec) -> Self {
+        PlanAction::Run(spec)
+    }
+}
+
+impl fmt::Display for PlanAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PlanAction::Run(spec) => write!(f, "$ {} {}", spec.program, spec.args.join(" ")),
+            PlanAction::RunConcurrently(specs) => {
+                let commands: Vec<String> = specs
+                    .iter()
+                    .map(|spec| format!("{} {}", spec.program, spec.args.join(" ")))
+                    .collect();
+                write!(f, "$ (concurrently) {}", commands.join(" & "))
+            }
+            PlanAction::RunInChroot(spec) => {
+                write!(f, "(chroot) $ {} {}", spec.program, spec.args.join(" "))
+            }
+            PlanAction::WriteFile { path, mode, .. } => write!(f, "write {path} (mode {mode:o})"),
+            PlanAction::Symlink { target, link } => write!(f, "link {link} -> {target}"),
+            PlanAction::CheckTools => write!(f, "check required tools"),
+            PlanAction::VerifyTarget(disk) => write!(f, "verify target disk {}", disk.path),
+            PlanAction::SaveTranscript => write!(f, "save command transcript"),
         }
     }
 }
@@ -58,22 +229,29 @@ pub enum InstallStage {
 pub fn build_plan(state: &InstallerState) -> Result<InstallPlan> {
     let mut steps = Vec::new();
 
+    // Check every tool the plan needs before anything touches the disk.
     steps.push(InstallStep::new(
         InstallStage::PrepareEnvironment,
         "Prepare live environment and validate selections",
-        Vec::new(),
+        vec![PlanAction::CheckTools],
     ));
 
     let (partition_summary, partition_commands, plan_opt) = if let Some(plan) = &state.target {
-        let plan_commands = partition::build_command_plan(plan)?.commands;
-        (
-            format!("Apply partition layout to {}", plan.target.path),
-            plan_commands,
-            Some(plan.clone()),
-        )
+        let plan_commands = build_partition_commands(plan)?;
+        let summary = if plan.resizes.is_empty() {
+            format!("Apply partition layout to {}", plan.target.path)
+        } else {
+            let shrunk: Vec<&str> = plan.resizes.iter().map(|r| r.path.as_str()).collect();
+            format!(
+                "Shrink {} and apply partition layout to {}",
+                shrunk.join(", "),
+                plan.target.path
+            )
+        };
+        (summary, plan_commands, Some(plan.clone()))
     } else if let Some(disk) = &state.selected_disk {
         let default_plan = partition::default_plan_for_disk(disk);
-        let plan_commands = partition::build_command_plan(&default_plan)?.commands;
+        let plan_commands = build_partition_commands(&default_plan)?;
         (
             format!("Partition disk {} using default layout", disk.path),
             plan_commands,
@@ -87,32 +265,49 @@ pub fn build_plan(state: &InstallerState) -> Result<InstallPlan> {
         )
     };
 
+    // Kernel names can change between probing and installing; check the
+    // disk is still the one the user picked right before writing to it.
+    let mut partition_actions = Vec::new();
+    if let Some(plan) = &plan_opt {
+        partition_actions.push(PlanAction::VerifyTarget(plan.target.clone()));
+    }
+    partition_actions.extend(into_actions(partition_commands));
+
     steps.push(InstallStep::new(
         InstallStage::PartitionDisks,
         partition_summary,
-        partition_commands.clone(),
+        partition_actions,
     ));
 
     let mut format_commands = Vec::new();
+    let mut swap_commands = Vec::new();
     let mut mount_commands = Vec::new();
     let mut mount_points: Vec<String> = Vec::new();
     let mut swap_devices: Vec<String> = Vec::new();
 
+    let discard = plan_opt
+        .as_ref()
+        .map(|plan| plan.discard)
+        .unwrap_or_default();
+
     if let Some(plan) = plan_opt {
-        for (index, spec) in plan.partitions.iter().enumerate() {
-            let device = partition_device_path(&plan.target.path, index + 1);
+        let numbers = partition::partition_numbers(&plan);
+        for (spec, number) in plan.partitions.iter().zip(numbers) {
+            let device = partition::partition_device_path(&plan.target.path, number);
 
             if let Some(mkfs) = filesystem::mkfs_command(&device, spec)? {
                 format_commands.push(mkfs);
             }
 
             if spec.filesystem == FileSystem::Swap {
-                format_commands.push(filesystem::activate_swap_command(&device));
+                swap_commands.push(filesystem::activate_swap_command(&device));
                 swap_devices.push(device);
                 continue;
             }
 
-            if let Some(command) = filesystem::mount_command(spec, &device, TARGET_ROOT)? {
+            if let Some(command) =
+                filesystem::mount_command(spec, &device, TARGET_ROOT, plan.discard)?
+            {
                 if let Some(target_path) = mount_target_path(spec, TARGET_ROOT) {
                     mount_points.push(target_path.clone());
                     mount_commands.push(mkdir_p_command(target_path));
@@ -125,61 +320,86 @@ pub fn build_plan(state: &InstallerState) -> Result<InstallPlan> {
     steps.push(InstallStep::new(
         InstallStage::FormatFilesystems,
         "Format selected partitions",
-        format_commands,
+        build_format_actions(format_commands, swap_commands),
     ));
 
     steps.push(InstallStep::new(
         InstallStage::MountTarget,
         format!("Mount target partitions under {}", TARGET_ROOT),
-        mount_commands,
+        into_actions(mount_commands),
     ));
 
     steps.push(InstallStep::new(
         InstallStage::InstallBaseSystem,
         "Install minimal Arch base system",
-        vec![packages::install_base_packages(TARGET_ROOT)],
+        vec![packages::install_base_packages(TARGET_ROOT).into()],
     ));
 
     steps.push(InstallStep::new(
         InstallStage::InstallDesktopEnvironment,
         "Install GNOME desktop packages",
-        vec![packages::install_desktop_packages(TARGET_ROOT)],
+        vec![packages::install_desktop_packages(TARGET_ROOT).into()],
     ));
 
     steps.push(InstallStep::new(
         InstallStage::ConfigureSystem,
         "Configure locale, users, networking, and services",
-        build_config_commands(),
+        build_config_actions(discard),
     ));
 
     steps.push(InstallStep::new(
         InstallStage::InstallBootloader,
         "Install and configure bootloader",
-        build_bootloader_commands(),
+        build_bootloader_actions(),
     ));
 
     steps.push(InstallStep::new(
         InstallStage::Finalize,
         "Finalize installation and clean up mounts",
-        build_finalize_commands(&mount_points, &swap_devices),
+        build_finalize_actions(&mount_points, &swap_devices),
     ));
 
-    Ok(InstallPlan::new(steps))
+    Ok(InstallPlan::new(TARGET_ROOT, steps))
 }
 
-fn partition_device_path(disk_path: &str, index: usize) -> String {
-    if disk_path.contains("nvme") || disk_path.contains("mmcblk") {
-        format!("{}p{}", disk_path, index)
-    } else {
-        format!("{}{}", disk_path, index)
+/// Each mkfs touches its own partition, so they can run side by side; swap
+/// is only activated once formatting is done.
+fn build_format_actions(
+    format_commands: Vec<CommandSpec>,
+    swap_commands: Vec<CommandSpec>,
+) -> Vec<PlanAction> {
+    let mut actions = match format_commands.len() {
+        0 => Vec::new(),
+        1 => into_actions(format_commands),
+        _ => vec![PlanAction::RunConcurrently(format_commands)],
+    };
+    actions.extend(into_actions(swap_commands));
+    actions
+}
+
+/// The whole table is written by a single sfdisk call, so a failure cannot
+/// leave it half done. Partitions being shrunk to make room go first.
+/// Waiting for udev makes sure the partition nodes exist before formatting.
+fn build_partition_commands(plan: &DiskPlan) -> Result<Vec<CommandSpec>> {
+    let mut commands = Vec::new();
+    for shrink in &plan.resizes {
+        commands.extend(resize::shrink_commands(
+            shrink,
+            &plan.target.path,
+            plan.target.geometry.logical_sector_bytes,
+        )?);
     }
+    commands.push(sfdisk::apply_command(plan)?);
+    commands.push(CommandSpec::new("udevadm", vec!["settle".to_string()]));
+    Ok(commands)
+}
+
+fn into_actions(commands: Vec<CommandSpec>) -> Vec<PlanAction> {
+    commands.into_iter().map(PlanAction::Run).collect()
 }
 
 fn mkdir_p_command(path: String) -> CommandSpec {
-    CommandSpec::new(
-        "mkdir",
-        vec!["-p".into(), path],
-    )
+    CommandSpec::new("mkdir", vec!["-p".into(), path])
 }
 
 fn mount_target_path(spec: &PartitionSpec, root: &str) -> Option<String> {
@@ -193,10 +413,10 @@ fn mount_target_path(spec: &PartitionSpec, root: &str) -> Option<String> {
     }
 }
 
-fn build_finalize_commands(mount_points: &[String], swap_devices: &[String]) -> Vec<CommandSpec> {
-    let mut commands = Vec::new();
-
-    commands.push(CommandSpec::new("sync", Vec::<String>::new()));
+fn build_finalize_actions(mount_points: &[String], swap_devices: &[String]) -> Vec<PlanAction> {
+    // The transcript has to land in the target while it is still mounted.
+    let mut actions = vec![PlanAction::SaveTranscript];
+    let mut commands = vec![CommandSpec::new("sync", Vec::<String>::new())];
 
     for device in swap_devices.iter() {
         commands.push(filesystem::deactivate_swap_command(device));
@@ -215,122 +435,131 @@ fn build_finalize_commands(mount_points: &[String], swap_devices: &[String]) ->
         commands.push(CommandSpec::new("umount", vec![TARGET_ROOT.into()]));
     }
 
-    commands
+    actions.extend(into_actions(commands));
+    actions
 }
 
-fn build_config_commands() -> Vec<CommandSpec> {
-    let mut commands = Vec::new();
-
-    commands.push(CommandSpec::new(
-        "arch-chroot",
-        vec![
-            TARGET_ROOT.into(),
-            "sh".into(),
-            "-c".into(),
-            "locale-gen".into(),
-        ],
-    ));
-
-    commands.push(CommandSpec::new(
-        "arch-chroot",
-        vec![
-            TARGET_ROOT.into(),
-            "sh".into(),
-            "-c".into(),
-            "echo 'LANG=en_US.UTF-8' > /etc/locale.conf".into(),
-        ],
-    ));
-
-    commands.push(CommandSpec::new(
-        "arch-chroot",
-        vec![
-            TARGET_ROOT.into(),
-            "ln".into(),
-            "-sf".into(),
-            "/usr/share/zoneinfo/UTC".into(),
-            "/etc/localtime".into(),
-        ],
-    ));
-
-    commands.push(CommandSpec::new(
-        "arch-chroot",
-        vec![
-            TARGET_ROOT.into(),
-            "sh".into(),
-            "-c".into(),
-            "hwclock --systohc".into(),
-        ],
-    ));
+fn build_config_actions(discard: DiscardPolicy) -> Vec<PlanAction> {
+    let mut actions = vec![
+        PlanAction::in_chroot("locale-gen", Vec::new()),
+        PlanAction::write_file("/etc/locale.conf", "LANG=en_US.UTF-8\n"),
+        PlanAction::symlink("/usr/share/zoneinfo/UTC", "/etc/localtime"),
+        PlanAction::in_chroot("hwclock", vec!["--systohc".into()]),
+        PlanAction::in_chroot(
+            "useradd",
+            vec!["-m".into(), "-G".into(), "wheel".into(), "armuser".into()],
+        ),
+        PlanAction::RunInChroot(
+            CommandSpec::new("chpasswd", Vec::new()).stdin("armuser:armdistro\n"),
+        ),
+        PlanAction::WriteFile {
+            path: "/etc/sudoers.d/99-arm-distro".into(),
+            contents: "%wheel ALL=(ALL) NOPASSWD: ALL\n".into(),
+            mode: 0o440,
+        },
+    ];
+
+    actions.extend(
+        packages::enable_services_commands()
+            .into_iter()
+            .map(PlanAction::RunInChroot),
+    );
+    if discard == DiscardPolicy::Periodic {
+        actions.push(PlanAction::RunInChroot(packages::enable_fstrim_command()));
+    }
 
-    commands.push(CommandSpec::new(
-        "arch-chroot",
-        vec![
-            TARGET_ROOT.into(),
-            "useradd".into(),
-            "-m".into(),
-            "-G".into(),
-            "wheel".into(),
-            "armuser".into(),
-        ],
-    ));
+    actions
+}
 
-    commands.push(CommandSpec::new(
-        "arch-chroot",
-        vec![
-            TARGET_ROOT.into(),
-            "sh".into(),
-            "-c".into(),
-            "echo 'armuser:armdistro' | chpasswd".into(),
-        ],
-    ));
+fn build_bootloader_actions() -> Vec<PlanAction> {
+    vec![
+        PlanAction::in_chroot("bootctl", vec!["install".into()]),
+        PlanAction::write_file("/boot/loader/loader.conf", "default arch.conf\n"),
+        PlanAction::in_chroot("mkinitcpio", vec!["-P".into()]),
+    ]
+}
 
-    commands.push(CommandSpec::new(
-        "arch-chroot",
-        vec![
-            TARGET_ROOT.into(),
-            "sh".into(),
-            "-c".into(),
-            "echo '%wheel ALL=(ALL) NOPASSWD: ALL' > /etc/sudoers.d/99-arm-distro".into(),
-        ],
-    ));
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::DiskGeometry;
+
+    fn state_with_disk() -> InstallerState {
+        InstallerState {
+            selected_disk: Some(DiskIdentifier {
+                path: "/dev/nvme0n1".into(),
+                size_bytes: 64 * 1024 * 1024 * 1024,
+                label: None,
+                geometry: DiskGeometry::default(),
+                serial: Some("S1".into()),
+                wwn: None,
+                by_id: None,
+            }),
+            ..InstallerState::default()
+        }
+    }
 
-    commands.extend(packages::enable_services_commands(TARGET_ROOT));
+    fn step(plan: &InstallPlan, stage: InstallStage) -> &InstallStep {
+        plan.steps()
+            .iter()
+            .find(|step| step.stage == stage)
+            .expect("stage missing from plan")
+    }
 
-    commands
-}
+    fn programs(actions: &[PlanAction]) -> Vec<&str> {
+        actions
+            .iter()
+            .flat_map(|action| match action {
+                PlanAction::Run(spec) | PlanAction::RunInChroot(spec) => vec![spec],
+                PlanAction::RunConcurrently(specs) => specs.iter().collect(),
+                _ => Vec::new(),
+            })
+            .map(|spec| spec.program.as_str())
+            .collect()
+    }
 
-fn build_bootloader_commands() -> Vec<CommandSpec> {
-    let mut commands = Vec::new();
+    #[test]
+    fn default_layout_verifies_then_partitions_the_selected_disk() {
+        let plan = build_plan(&state_with_disk()).unwrap();
+
+        let partition = step(&plan, InstallStage::PartitionDisks);
+        assert!(matches!(
+            &partition.actions[0],
+            PlanAction::VerifyTarget(disk) if disk.path == "/dev/nvme0n1"
+        ));
+        assert_eq!(programs(&partition.actions), vec!["sfdisk", "udevadm"]);
+
+        let format = step(&plan, InstallStage::FormatFilesystems);
+        let PlanAction::RunConcurrently(mkfs) = &format.actions[0] else {
+            panic!("filesystems should be created concurrently");
+        };
+        let devices: Vec<&str> = mkfs
+            .iter()
+            .filter_map(|spec| spec.args.last())
+            .map(String::as_str)
+            .collect();
+        assert_eq!(devices, vec!["/dev/nvme0n1p1", "/dev/nvme0n1p2"]);
+    }
 
-    commands.push(CommandSpec::new(
-        "arch-chroot",
-        vec![
-            TARGET_ROOT.into(),
-            "sh".into(),
-            "-c".into(),
-            "bootctl install".into(),
-        ],
-    ));
+    #[test]
+    fn no_selected_disk_skips_partitioning() {
+        let plan = build_plan(&InstallerState::default()).unwrap();
 
-    commands.push(CommandSpec::new(
-        "arch-chroot",
-        vec![
-            TARGET_ROOT.into(),
-            "sh".into(),
-            "-c".into(),
-            "echo 'default arch.conf' > /boot/loader/loader.conf".into(),
-        ],
-    ));
+        assert!(step(&plan, InstallStage::PartitionDisks).actions.is_empty());
+        assert!(step(&plan, InstallStage::FormatFilesystems)
+            .actions
+            .is_empty());
+    }
 
-    commands.push(CommandSpec::new(
-        "arch-chroot",
-        vec![
-            TARGET_ROOT.into(),
-            "sh".into(),
-            "-c".into(),
-            "mkinitcpio -P".into(),
-        ],
-    ));
+    #[test]
+    fn device_names_are_renamed_only_for_the_retargeted_disk() {
+        let rename = |word| rename_device(word, "/dev/nvme0n1", "/dev/sda");
 
-    commands
+        assert_eq!(rename("/dev/nvme0n1").as_deref(), Some("/dev/sda"));
+        assert_eq!(rename("/dev/nvme0n1p2").as_deref(), Some("/dev/sda2"));
+        assert_eq!(rename("/dev/nvme0n12"), None);
+        assert_eq!(rename("/dev/nvme0n1p"), None);
+        assert_eq!(rename("/dev/nvme1n1p2"), None);
+        assert_eq!(rename("/mnt/arm-distro"), None);
+    }
 }
@@ -0,0 +1,190 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use parking_lot::Mutex;
+use serde::Serialize;
+
+use crate::backend::command::{CommandOutput, CommandSpec};
+use crate::backend::files;
+
+/// Where the live system keeps the transcript; the same path is used inside
+/// the installed system.
+pub const TRANSCRIPT_DIR: &str = "/var/log/arm-installer";
+pub const TRANSCRIPT_FILE: &str = "transcript.jsonl";
+
+/// Output beyond this many bytes per stream is cut, keeping the tail where
+/// errors usually are.
+const MAX_OUTPUT_BYTES: usize = 64 * 1024;
+
+/// One executed command. Timestamps are milliseconds since the Unix epoch.
+/// Stdin and environment are deliberately left out as they may hold secrets.
+#[derive(Debug, Clone, Serialize)]
+pub struct TranscriptRecord {
+    pub program: String,
+    pub args: Vec<String>,
+    pub started_at_ms: u64,
+    pub finished_at_ms: u64,
+    pub duration_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exit_code: Option<i32>,
+    pub stdout: String,
+    pub stdout_truncated: bool,
+    pub stderr: String,
+    pub stderr_truncated: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl TranscriptRecord {
+    pub fn new(
+        spec: &CommandSpec,
+        started_at: SystemTime,
+        duration: Duration,
+        result: &Result<CommandOutput>,
+    ) -> Self {
+        let started_at_ms = unix_millis(started_at);
+        let duration_ms = u64::try_from(duration.as_millis()).unwrap_or(u64::MAX);
+
+        let mut record = Self {
+            program: spec.program.clone(),
+            args: spec.args.clone(),
+            started_at_ms,
+            finished_at_ms: started_at_ms.saturating_add(duration_ms),
+            duration_ms,
+            exit_code: None,
+            stdout: String::new(),
+            stdout_truncated: false,
+            stderr: String::new(),
+            stderr_truncated: false,
+            error: None,
+        };
+
+        match result {
+            Ok(output) => {
+                record.program = output.program().to_string();
+                record.args = output.args().to_vec();
+                record.exit_code = output.status.code();
+                (record.stdout, record.stdout_truncated) = truncate_tail(&output.stdout);
+                (record.stderr, record.stderr_truncated) = truncate_tail(&output.stderr);
+            }
+            Err(err) => record.error = Some(format!("{err:#}")),
+        }
+
+        record
+    }
+}
+
+/// Append-only JSON Lines log of every command the installer runs.
+#[derive(Debug)]
+pub struct Transcript {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+impl Transcript {
+    pub fn open(dir: &Path) -> Result<Self> {
+        fs::create_dir_all(dir).with_context(|| format!("failed to create {}", dir.display()))?;
+        let path = dir.join(TRANSCRIPT_FILE);
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("failed to open {}", path.display()))?;
+
+        Ok(Self {
+            path,
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn record(&self, record: &TranscriptRecord) -> Result<()> {
+        let mut line = serde_json::to_string(record).context("failed to encode transcript")?;
+        line.push('\n');
+
+        let mut file = self.file.lock();
+        file.write_all(line.as_bytes())
+            .and_then(|_| file.flush())
+            .with_context(|| format!("failed to write {}", self.path.display()))
+    }
+
+    /// Copy the transcript into the installed system under `root`.
+    pub fn copy_into(&self, root: &str) -> Result<PathBuf> {
+        let target_dir = files::target_path(root, TRANSCRIPT_DIR)?;
+        fs::create_dir_all(&target_dir)
+            .with_context(|| format!("failed to create {}", target_dir.display()))?;
+
+        let destination = target_dir.join(TRANSCRIPT_FILE);
+        // Hold the lock so no record is half-written while copying.
+        let _guard = self.file.lock();
+        fs::copy(&self.path, &destination).with_context(|| {
+            format!(
+                "failed to copy {} to {}",
+                self.path.display(),
+                destination.display()
+            )
+        })?;
+
+        Ok(destination)
+    }
+}
+
+fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|elapsed| u64::try_from(elapsed.as_millis()).unwrap_or(u64::MAX))
+        .unwrap_or(0)
+}
+
+fn truncate_tail(value: &str) -> (String, bool) {
+    if value.len() <= MAX_OUTPUT_BYTES {
+        return (value.to_string(), false);
+    }
+
+    let mut start = value.len() - MAX_OUTPUT_BYTES;
+    while !value.is_char_boundary(start) {
+        start += 1;
+    }
+    (value[start..].to_string(), true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn output_within_the_limit_is_kept() {
+        let exact = "x".repeat(MAX_OUTPUT_BYTES);
+
+        assert_eq!(truncate_tail(""), (String::new(), false));
+        assert_eq!(truncate_tail("short"), ("short".to_string(), false));
+        assert_eq!(truncate_tail(&exact), (exact.clone(), false));
+    }
+
+    #[test]
+    fn long_output_keeps_its_tail() {
+        let value = format!("head{}", "x".repeat(MAX_OUTPUT_BYTES));
+
+        let (tail, truncated) = truncate_tail(&value);
+
+        assert!(truncated);
+        assert_eq!(tail.len(), MAX_OUTPUT_BYTES);
+        assert!(tail.starts_with('x'));
+    }
+
+    #[test]
+    fn truncation_does_not_split_a_character() {
+        // The cut would land in the middle of the leading "é" (two bytes).
+        let value = format!("aé{}", "x".repeat(MAX_OUTPUT_BYTES - 1));
+
+        let (tail, truncated) = truncate_tail(&value);
+
+        assert!(truncated);
+        assert_eq!(tail, "x".repeat(MAX_OUTPUT_BYTES - 1));
+    }
+}
@@ -9,8 +9,9 @@ pub struct InstallerState {
     pub target: Option<DiskPlan>,
     pub users: Vec<UserAccount>,
     pub network: NetworkConfig,
-    pub discovered_disks: Vec<DiskIdentifier>,
+    pub disk_inventory: DiskInventory,
     pub selected_disk: Option<DiskIdentifier>,
+    pub platform: Platform,
 }
 
 impl Default for InstallerState {
@@ -23,8 +24,57 @@ impl Default for InstallerState {
             target: None,
             users: vec![UserAccount::default_admin()],
             network: NetworkConfig::default(),
-            discovered_disks: Vec::new(),
+            disk_inventory: DiskInventory::default(),
             selected_disk: None,
+            platform: Platform::Unknown,
+        }
+    }
+}
+
+/// The kind of machine the installer is running on.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub enum Platform {
+    /// Apple M-series Mac, booted through m1n1/U-Boot.
+    AppleSilicon {
+        chip: String,
+    },
+    /// QEMU (with or without KVM), including the `virt` machine.
+    QemuVirt,
+    /// Any other machine booted through UEFI, physical or virtual.
+    GenericUefi {
+        vendor: Option<String>,
+        product: Option<String>,
+    },
+    /// Device-tree board without UEFI, identified by its most specific
+    /// compatible string.
+    Board {
+        compatible: String,
+        model: Option<String>,
+    },
+    Unknown,
+}
+
+impl Platform {
+    pub fn describe(&self) -> String {
+        match self {
+            Platform::AppleSilicon { chip } => format!("Apple Silicon {chip}"),
+            Platform::QemuVirt => "QEMU virtual machine".into(),
+            Platform::GenericUefi { vendor, product } => {
+                let name: Vec<&str> = [vendor, product]
+                    .into_iter()
+                    .flatten()
+                    .map(String::as_str)
+                    .collect();
+                if name.is_empty() {
+                    "Generic UEFI system".into()
+                } else {
+                    format!("UEFI system ({})", name.join(" "))
+                }
+            }
+            Platform::Board { compatible, model } => {
+                model.clone().unwrap_or_else(|| compatible.clone())
+            }
+            Platform::Unknown => "Unknown platform".into(),
         }
     }
 }
@@ -64,6 +114,11 @@ pub struct DiskPlan {
     pub target: DiskIdentifier,
     pub mode: DiskMode,
     pub partitions: Vec<PartitionSpec>,
+    #[serde(default)]
+    pub discard: DiscardPolicy,
+    /// Existing partitions shrunk before the new ones are created.
+    #[serde(default)]
+    pub resizes: Vec<PartitionResize>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
@@ -71,12 +126,307 @@ pub struct DiskIdentifier {
     pub path: String,
     pub size_bytes: u64,
     pub label: Option<String>,
+    #[serde(default)]
+    pub geometry: DiskGeometry,
+    #[serde(default)]
+    pub serial: Option<String>,
+    /// World Wide Name, e.g. `0x5002538e40a1b2c3`.
+    #[serde(default)]
+    pub wwn: Option<String>,
+    /// Stable `/dev/disk/by-id` symlink pointing at the disk.
+    #[serde(default)]
+    pub by_id: Option<String>,
+}
+
+impl DiskIdentifier {
+    /// Whether anything beyond the kernel name identifies the disk.
+    pub fn has_stable_identity(&self) -> bool {
+        self.serial.is_some() || self.wwn.is_some() || self.by_id.is_some()
+    }
+}
+
+/// Sector and I/O characteristics the partition planner aligns to.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct DiskGeometry {
+    pub logical_sector_bytes: u64,
+    pub physical_sector_bytes: u64,
+    /// Preferred I/O size, 0 when the device does not report one.
+    pub optimal_io_bytes: u64,
+    /// 0 when the device does not support discard.
+    pub discard_granularity_bytes: u64,
+    pub rotational: bool,
+}
+
+impl Default for DiskGeometry {
+    fn default() -> Self {
+        Self {
+            logical_sector_bytes: 512,
+            physical_sector_bytes: 512,
+            optimal_io_bytes: 0,
+            discard_granularity_bytes: 0,
+            rotational: false,
+        }
+    }
+}
+
+impl DiskGeometry {
+    pub fn supports_discard(&self) -> bool {
+        self.discard_granularity_bytes > 0
+    }
+}
+
+/// How freed blocks are reported to the device.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default)]
+pub enum DiscardPolicy {
+    #[default]
+    Disabled,
+    /// Weekly `fstrim.timer`.
+    Periodic,
+    /// Mount with the `discard` option.
+    Continuous,
+}
+
+impl DiscardPolicy {
+    /// Periodic TRIM for solid-state devices that support discard; nothing
+    /// for spinning disks.
+    pub fn for_geometry(geometry: &DiskGeometry) -> Self {
+        if geometry.supports_discard() && !geometry.rotational {
+            DiscardPolicy::Periodic
+        } else {
+            DiscardPolicy::Disabled
+        }
+    }
+
+    pub fn describe(&self) -> &'static str {
+        match self {
+            DiscardPolicy::Disabled => "TRIM disabled",
+            DiscardPolicy::Periodic => "periodic TRIM (fstrim.timer)",
+            DiscardPolicy::Continuous => "continuous TRIM (discard mount option)",
+        }
+    }
+}
+
+/// Everything lsblk reported about the disks attached to the machine.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct DiskInventory {
+    pub disks: Vec<DiskDevice>,
+}
+
+impl DiskInventory {
+    pub fn identifiers(&self) -> Vec<DiskIdentifier> {
+        self.disks
+            .iter()
+            .map(|disk| disk.identifier.clone())
+            .collect()
+    }
+
+    pub fn find(&self, path: &str) -> Option<&DiskDevice> {
+        self.disks.iter().find(|disk| disk.identifier.path == path)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct DiskDevice {
+    pub identifier: DiskIdentifier,
+    pub transport: Option<String>,
+    pub read_only: bool,
+    pub removable: bool,
+    /// Partition table type as reported by lsblk (`gpt`, `dos`).
+    pub partition_table: Option<String>,
+    /// Filesystem written directly to the disk, e.g. an ISO image.
+    pub filesystem: Option<String>,
+    pub mountpoints: Vec<String>,
+    pub partitions: Vec<BlockNode>,
+    /// Set when the disk must not be offered as an install target.
+    pub ineligible: Option<Ineligibility>,
+    /// Operating systems found on the disk that installing would destroy.
+    pub existing_systems: Vec<ExistingSystem>,
+    /// Unallocated gaps usable with [`DiskMode::FreeSpace`].
+    pub free_regions: Vec<FreeSpaceRegion>,
+}
+
+impl DiskDevice {
+    pub fn is_eligible(&self) -> bool {
+        self.ineligible.is_none()
+    }
+
+    /// Every node below the disk, depth first.
+    pub fn descendants(&self) -> Vec<&BlockNode> {
+        let mut nodes = Vec::new();
+        for partition in &self.partitions {
+            partition.collect_into(&mut nodes);
+        }
+        nodes
+    }
+
+    /// Whether the disk or anything on it is mounted.
+    pub fn is_mounted(&self) -> bool {
+        !self.mountpoints.is_empty()
+            || self
+                .descendants()
+                .iter()
+                .any(|node| !node.mountpoints.is_empty())
+    }
+}
+
+/// Why a disk cannot be used as the install target.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub enum Ineligibility {
+    /// The medium the live system was booted from.
+    LiveMedium,
+    ReadOnly,
+    TooSmall {
+        minimum_bytes: u64,
+    },
+    /// The disk or one of its partitions is in use.
+    Mounted,
+    /// Compressed RAM block device, not real storage.
+    Zram,
+}
+
+impl Ineligibility {
+    pub fn describe(&self) -> String {
+        match self {
+            Ineligibility::LiveMedium => "live boot medium".into(),
+            Ineligibility::ReadOnly => "read-only".into(),
+            Ineligibility::TooSmall { minimum_bytes } => format!(
+                "smaller than minimum ({} GiB)",
+                minimum_bytes / (1024 * 1024 * 1024)
+            ),
+            Ineligibility::Mounted => "currently mounted".into(),
+            Ineligibility::Zram => "zram swap device".into(),
+        }
+    }
+}
+
+/// An operating system (or encrypted volume that may hold one) found on a
+/// partition.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ExistingSystem {
+    pub kind: ExistingSystemKind,
+    /// Device node the system was found on.
+    pub path: String,
+    /// Human readable name, e.g. the `PRETTY_NAME` from os-release.
+    pub name: String,
+}
+
+impl ExistingSystem {
+    pub fn describe(&self) -> String {
+        format!("{} on {}", self.name, self.path)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum ExistingSystemKind {
+    MacOs,
+    Windows,
+    BitLocker,
+    Linux,
+}
+
+/// A partition or a device stacked on one (LUKS mapping, LVM volume, ...).
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct BlockNode {
+    pub name: String,
+    pub path: String,
+    /// lsblk device type: `part`, `crypt`, `lvm`, ...
+    pub kind: String,
+    pub size_bytes: u64,
+    pub filesystem: Option<String>,
+    pub label: Option<String>,
+    pub partlabel: Option<String>,
+    /// GPT partition type GUID or MBR type code.
+    pub parttype: Option<String>,
+    pub uuid: Option<String>,
+    pub partuuid: Option<String>,
+    pub mountpoints: Vec<String>,
+    pub read_only: bool,
+    /// Offset of a partition from the start of its disk.
+    #[serde(default)]
+    pub start_bytes: Option<u64>,
+    pub children: Vec<BlockNode>,
+}
+
+impl BlockNode {
+    fn collect_into<'a>(&'a self, nodes: &mut Vec<&'a BlockNode>) {
+        nodes.push(self);
+        for child in &self.children {
+            child.collect_into(nodes);
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub enum DiskMode {
     UseEntireDisk,
     Custom,
+    /// Create the partitions inside an unallocated gap, leaving the existing
+    /// partition table and partitions untouched.
+    FreeSpace(FreeSpaceRegion),
+}
+
+/// An unallocated gap in an existing GPT partition table.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct FreeSpaceRegion {
+    /// First byte of the gap, aligned to 1 MiB.
+    pub start_bytes: u64,
+    /// End of the gap (exclusive), aligned to 1 MiB.
+    pub end_bytes: u64,
+    /// Partition numbers already in use on the disk. New partitions take the
+    /// lowest free numbers, as sfdisk assigns them.
+    pub used_numbers: Vec<u32>,
+}
+
+impl FreeSpaceRegion {
+    pub fn size_bytes(&self) -> u64 {
+        self.end_bytes.saturating_sub(self.start_bytes)
+    }
+}
+
+/// An existing partition shrunk to make room for the install. The
+/// filesystem is shrunk first, then the partition around it.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct PartitionResize {
+    pub path: String,
+    pub number: u32,
+    pub filesystem: ResizableFilesystem,
+    pub start_bytes: u64,
+    pub current_bytes: u64,
+    pub new_bytes: u64,
+}
+
+impl PartitionResize {
+    pub fn freed_bytes(&self) -> u64 {
+        self.current_bytes.saturating_sub(self.new_bytes)
+    }
+}
+
+/// Filesystems that can be shrunk in place.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum ResizableFilesystem {
+    Ext4,
+    Btrfs,
+    Ntfs,
+}
+
+impl ResizableFilesystem {
+    /// Match an lsblk `FSTYPE`.
+    pub fn from_fstype(fstype: &str) -> Option<Self> {
+        match fstype {
+            "ext4" => Some(Self::Ext4),
+            "btrfs" => Some(Self::Btrfs),
+            "ntfs" => Some(Self::Ntfs),
+            _ => None,
+        }
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            Self::Ext4 => "ext4",
+            Self::Btrfs => "btrfs",
+            Self::Ntfs => "ntfs",
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -88,7 +438,7 @@ pub struct PartitionSpec {
     pub flags: Vec<PartitionFlag>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub enum PartitionSize {
     ExactBytes(u64),
     Percentage(u8),
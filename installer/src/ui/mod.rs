@@ -1,22 +1,31 @@
 use std::rc::Rc;
 use std::sync::Arc;
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use parking_lot::RwLock;
 use slint::{ModelRc, SharedString, VecModel};
 use tracing::{error, info, warn};
 
-use crate::backend::{partition, Backend};
+use crate::backend::command::CancellationToken;
+use crate::backend::editor::{self, PartitionEdit};
+use crate::backend::hotplug::DiskWatcher;
+use crate::backend::partition::{self, PlanError};
 use crate::backend::tasks::InstallPlan;
-use crate::state::{DiskIdentifier, InstallerState};
+use crate::backend::{disk, resize, Backend, InstallOutcome};
+use crate::state::{
+    DiskDevice, DiskInventory, DiskMode, DiskPlan, FreeSpaceRegion, InstallerState, PartitionSize,
+};
 
 slint::include_modules!();
 
 pub struct App {
     window: AppWindow,
     backend: Backend,
+    #[allow(dead_code)]
     state: Arc<RwLock<InstallerState>>,
     _plan: Arc<RwLock<Option<InstallPlan>>>,
+    _cancel: Arc<RwLock<Option<CancellationToken>>>,
+    _watcher: Arc<DiskWatcher>,
 }
 
 impl App {
@@ -24,26 +33,56 @@ impl App {
         let window = AppWindow::new()?;
         window.set_installing(false);
         let plan_holder: Arc<RwLock<Option<InstallPlan>>> = Arc::new(RwLock::new(None));
+        let cancel_holder: Arc<RwLock<Option<CancellationToken>>> = Arc::new(RwLock::new(None));
 
         let steps = init_steps(&window, &state);
 
         window.set_install_plan_summary(SharedString::new());
         window.set_install_log(SharedString::new());
 
-        match backend.list_disks() {
-            Ok(disks) => {
-                info!(count = disks.len(), "discovered block devices");
-                apply_disk_inventory(&window, &state, disks);
+        match backend.probe_inventory() {
+            Ok(inventory) => {
+                info!(count = inventory.disks.len(), "discovered block devices");
+                apply_disk_inventory(&window, &state, inventory);
             }
             Err(err) => {
                 warn!("failed to probe block devices: {:#}", err);
                 window.set_disk_items(ModelRc::<DiskItem>::default());
             }
         }
+        let platform = backend.detect_platform();
+        info!(?platform, "detected platform");
+        window.set_platform_summary(SharedString::from(platform.describe()));
+        apply_preflight(&window, &backend);
+
+        // Keep the disk list current while the wizard is open.
+        let watch_state = state.clone();
+        let watch_plan = Arc::clone(&plan_holder);
+        let watch_weak = window.as_weak();
+        let watcher = Arc::new(backend.watch_disks(move |inventory| {
+            let state = watch_state.clone();
+            let plan_store = Arc::clone(&watch_plan);
+            let window_weak = watch_weak.clone();
+            let _ = slint::invoke_from_event_loop(move || {
+                if let Some(window) = window_weak.upgrade() {
+                    if apply_disk_inventory(&window, &state, inventory) {
+                        warn!("selected disk was removed; selection cleared");
+                        plan_store.write().take();
+                        window.set_install_plan_summary(SharedString::new());
+                        window.set_disk_selection_summary(SharedString::from(
+                            "Selected disk was removed",
+                        ));
+                    }
+                }
+            });
+        }));
+
         let next_state = state.clone();
         let steps_for_next = Arc::clone(&steps);
         let backend_for_install = backend.clone();
         let plan_store = Arc::clone(&plan_holder);
+        let cancel_store = Arc::clone(&cancel_holder);
+        let watcher_for_install = Arc::clone(&watcher);
         let next_weak = window.as_weak();
         window.on_request_next(move || {
             if let Some(window) = next_weak.upgrade() {
@@ -55,6 +94,9 @@ impl App {
                     next_state.write().current_step = new_idx as usize;
                     update_current_step_labels(&window, &steps_for_next, new_idx as usize);
                     window.set_install_log(SharedString::new());
+                    if new_idx as usize == FILESYSTEM_STEP {
+                        apply_partition_dry_run(&window, &backend_for_install);
+                    }
                 } else {
                     let plan = match plan_store.read().clone() {
                         Some(plan) => plan,
@@ -74,24 +116,44 @@ impl App {
                         },
                     };
 
+                    let cancel = CancellationToken::new();
+                    cancel_store.write().replace(cancel.clone());
+
+                    // Rescanning would mount the partitions being created.
+                    watcher_for_install.pause();
                     window.set_installing(true);
                     window.set_install_log(SharedString::from("Running installation..."));
                     let window_for_log = window.as_weak();
                     let backend_runner = backend_for_install.clone();
+                    let cancel_done = Arc::clone(&cancel_store);
+                    let watcher_done = Arc::clone(&watcher_for_install);
                     std::thread::spawn(move || {
-                        let run_result = backend_runner.execute_plan_stream(plan.clone(), |line| {
-                            let window_for_log = window_for_log.clone();
-                            let line_owned = line.clone();
-                            let _ = slint::invoke_from_event_loop(move || {
-                                if let Some(window) = window_for_log.upgrade() {
-                                    append_log(&window, &line_owned);
-                                }
+                        let run_result =
+                            backend_runner.execute_plan_stream(plan.clone(), &cancel, |line| {
+                                let window_for_log = window_for_log.clone();
+                                let line_owned = line.clone();
+                                let _ = slint::invoke_from_event_loop(move || {
+                                    if let Some(window) = window_for_log.upgrade() {
+                                        append_log(&window, &line_owned);
+                                    }
+                                });
                             });
-                        });
+
+                        cancel_done.write().take();
 
                         let final_message = match run_result {
-                            Ok(true) => "Installation completed successfully".to_string(),
-                            Ok(false) => "Installation stopped due to errors".to_string(),
+                            Ok(InstallOutcome::Completed) => {
+                                "Installation completed successfully".to_string()
+                            }
+                            Ok(InstallOutcome::Failed) => {
+                                "Installation stopped due to errors".to_string()
+                            }
+                            Ok(InstallOutcome::Cancelled) => "Installation cancelled".to_string(),
+                            Ok(InstallOutcome::TimedOut { program, timeout }) => {
+                                format!(
+                                    "Installation stopped: {program} timed out after {timeout:?}"
+                                )
+                            }
                             Err(err) => format!("Execution failed: {err:#}"),
                         };
 
@@ -100,7 +162,9 @@ impl App {
                             if let Some(window) = window_for_log.upgrade() {
                                 append_log(&window, &final_message);
                                 window.set_installing(false);
+                                window.set_cancel_pending(false);
                             }
+                            watcher_done.resume();
                         });
                     });
                 }
@@ -122,10 +186,122 @@ impl App {
             }
         });
 
+        let cancel_request = Arc::clone(&cancel_holder);
         let cancel_weak = window.as_weak();
         window.on_request_cancel(move || {
             if let Some(window) = cancel_weak.upgrade() {
                 info!(step = window.get_current_step_index(), "cancel pressed");
+                if let Some(cancel) = cancel_request.read().as_ref() {
+                    cancel.cancel();
+                    append_log(&window, "Cancelling installation...");
+                }
+            }
+        });
+
+        window.set_filesystem_options(ModelRc::from(
+            editor::EDITOR_FILESYSTEMS
+                .iter()
+                .map(|fs| SharedString::from(*fs))
+                .collect::<Vec<_>>()
+                .as_slice(),
+        ));
+
+        let edit_backend = backend.clone();
+        let edit_state = state.clone();
+        let edit_plan = Arc::clone(&plan_holder);
+        let edit_weak = window.as_weak();
+        window.on_partition_field_edited(move |index, field, value| {
+            if let Some(window) = edit_weak.upgrade() {
+                let edit = parse_field_edit(index as usize, &field, &value);
+                handle_layout_edit(&window, &edit_backend, &edit_state, &edit_plan, edit, false);
+            }
+        });
+
+        let add_backend = backend.clone();
+        let add_state = state.clone();
+        let add_plan = Arc::clone(&plan_holder);
+        let add_weak = window.as_weak();
+        window.on_add_partition(move || {
+            if let Some(window) = add_weak.upgrade() {
+                handle_layout_edit(
+                    &window,
+                    &add_backend,
+                    &add_state,
+                    &add_plan,
+                    Ok(PartitionEdit::Add),
+                    true,
+                );
+            }
+        });
+
+        let remove_backend = backend.clone();
+        let remove_state = state.clone();
+        let remove_plan = Arc::clone(&plan_holder);
+        let remove_weak = window.as_weak();
+        window.on_remove_partition(move |index| {
+            if let Some(window) = remove_weak.upgrade() {
+                let edit = PartitionEdit::Remove {
+                    index: index as usize,
+                };
+                handle_layout_edit(
+                    &window,
+                    &remove_backend,
+                    &remove_state,
+                    &remove_plan,
+                    Ok(edit),
+                    true,
+                );
+            }
+        });
+
+        let size_backend = backend.clone();
+        let size_state = state.clone();
+        let size_plan = Arc::clone(&plan_holder);
+        let size_weak = window.as_weak();
+        window.on_root_size_changed(move |mode, gib, percent| {
+            if let Some(window) = size_weak.upgrade() {
+                handle_root_size(
+                    &window,
+                    &size_backend,
+                    &size_state,
+                    &size_plan,
+                    mode,
+                    &gib,
+                    percent,
+                );
+            }
+        });
+
+        let shrink_backend = backend.clone();
+        let shrink_state = state.clone();
+        let shrink_plan = Arc::clone(&plan_holder);
+        let shrink_weak = window.as_weak();
+        window.on_shrink_partition(move |index, size| {
+            if let Some(window) = shrink_weak.upgrade() {
+                handle_shrink(
+                    &window,
+                    &shrink_backend,
+                    &shrink_state,
+                    &shrink_plan,
+                    index as usize,
+                    &size,
+                );
+            }
+        });
+
+        let free_backend = backend.clone();
+        let free_state = state.clone();
+        let free_plan = Arc::clone(&plan_holder);
+        let free_weak = window.as_weak();
+        window.on_use_free_space(move |index| {
+            if let Some(window) = free_weak.upgrade() {
+                handle_free_space(
+                    &window,
+                    &free_backend,
+                    &free_state,
+                    &free_plan,
+                    index as usize,
+                );
             }
         });
 
@@ -143,6 +319,8 @@ impl App {
             backend,
             state,
             _plan: plan_holder,
+            _cancel: cancel_holder,
+            _watcher: watcher,
         })
     }
 
@@ -183,19 +361,46 @@ fn update_current_step_labels(window: &AppWindow, steps: &Arc<Vec<StepData>>, in
     }
 }
 
+/// Store a fresh inventory and refresh the disk list. The selection is kept
+/// while the selected disk is still attached, following it to a new kernel
+/// name by its stable identity; returns `true` when it had to be dropped.
 fn apply_disk_inventory(
     window: &AppWindow,
     state: &Arc<RwLock<InstallerState>>,
-    disks: Vec<DiskIdentifier>,
-) {
-    let selected_path = {
+    inventory: DiskInventory,
+) -> bool {
+    let (selected_path, dropped) = {
         let mut guard = state.write();
-        guard.discovered_disks = disks.clone();
-        guard.selected_disk.as_ref().map(|disk| disk.path.clone())
+        let resolved = guard
+            .selected_disk
+            .as_ref()
+            .map(|selected| disk::resolve_identity(selected, &inventory));
+        let dropped = match resolved {
+            Some(Ok(current)) => {
+                if guard.selected_disk.as_ref() != Some(&current) {
+                    info!(path = current.path, "selected disk changed, following it");
+                    partition::retarget(&mut guard, &current);
+                }
+                false
+            }
+            Some(Err(err)) => {
+                warn!("dropping the disk selection: {:#}", err);
+                guard.selected_disk = None;
+                guard.target = None;
+                true
+            }
+            None => false,
+        };
+        guard.disk_inventory = inventory.clone();
+        (
+            guard.selected_disk.as_ref().map(|disk| disk.path.clone()),
+            dropped,
+        )
     };
 
-    let items_vec: Vec<DiskItem> = disks
-        .into_iter()
+    let items_vec: Vec<DiskItem> = inventory
+        .disks
+        .iter()
         .map(|disk| disk_to_item(disk, selected_path.as_deref()))
         .collect();
     let model: ModelRc<DiskItem> = Rc::new(VecModel::from(items_vec)).into();
@@ -206,11 +411,399 @@ fn apply_disk_inventory(
         build_disk_summary(&guard)
     };
     window.set_disk_selection_summary(summary);
-    window.set_install_plan_summary(SharedString::new());
-    window.set_install_log(SharedString::new());
+    let warning = selected_path
+        .as_deref()
+        .and_then(|path| inventory.find(path))
+        .and_then(existing_systems_warning)
+        .unwrap_or_default();
+    window.set_target_warning(SharedString::from(warning));
+    let discard = {
+        let guard = state.read();
+        guard
+            .target
+            .as_ref()
+            .map(|plan| format!("Discard: {}", plan.discard.describe()))
+            .unwrap_or_default()
+    };
+    window.set_discard_summary(SharedString::from(discard));
+    apply_partition_editor(window, state);
+    apply_size_control(window, state);
+
+    dropped
+}
+
+fn apply_preflight(window: &AppWindow, backend: &Backend) {
+    match backend.preflight() {
+        Ok(report) => {
+            for tool in &report.tools {
+                info!(program = tool.program, path = ?tool.path, version = ?tool.version, "preflight tool");
+            }
+            let mut lines = vec![report.describe()];
+            lines.extend(report.tools.iter().filter_map(|tool| {
+                tool.version
+                    .as_ref()
+                    .map(|version| format!("  {}: {version}", tool.program))
+            }));
+            window.set_preflight_ready(report.is_ready());
+            window.set_preflight_summary(SharedString::from(lines.join("\n")));
+        }
+        Err(err) => {
+            warn!("preflight check failed: {:#}", err);
+            window.set_preflight_ready(false);
+            window.set_preflight_summary(SharedString::from(format!(
+                "Preflight check failed: {err:#}"
+            )));
+        }
+    }
+}
+
+/// Rebuild the partition editor rows from the target plan.
+fn apply_partition_editor(window: &AppWindow, state: &Arc<RwLock<InstallerState>>) {
+    let guard = state.read();
+    let items: Vec<PartitionItem> = guard
+        .target
+        .iter()
+        .flat_map(|plan| plan.partitions.iter())
+        .map(|spec| PartitionItem {
+            id: spec.id.clone().into(),
+            mountpoint: spec.mountpoint.clone().unwrap_or_default().into(),
+            filesystem: spec.filesystem.label().into(),
+            size: editor::format_size(&spec.size).into(),
+            flags: editor::format_flags(&spec.flags).into(),
+        })
+        .collect();
+    let problems = describe_plan_errors(&partition::validate_target(&guard));
+    let editable = guard.target.is_some() || guard.selected_disk.is_some();
+    let candidates: Vec<SharedString> = shrink_candidates(&guard)
+        .into_iter()
+        .map(|(_, label)| label.into())
+        .collect();
+    let regions: Vec<SharedString> = guard
+        .selected_disk
+        .as_ref()
+        .and_then(|disk| guard.disk_inventory.find(&disk.path))
+        .map(|device| device.free_regions.iter().map(describe_region).collect())
+        .unwrap_or_default();
+    let preview = guard
+        .target
+        .as_ref()
+        .map(describe_shrink)
+        .unwrap_or_default();
+    let unallocated = guard
+        .target
+        .as_ref()
+        .map(|plan| match partition::unallocated_bytes(plan) {
+            Ok(bytes) => format!("Left unallocated: {}", human_readable_bytes(bytes)),
+            Err(err) => format!("The layout does not fit: {err:#}"),
+        })
+        .unwrap_or_default();
+    drop(guard);
+
+    let model: ModelRc<PartitionItem> = Rc::new(VecModel::from(items)).into();
+    window.set_partition_items(model);
+    window.set_layout_problems(SharedString::from(problems));
+    window.set_layout_editable(editable);
+    window.set_shrink_candidates(ModelRc::from(candidates.as_slice()));
+    window.set_shrink_preview(SharedString::from(preview));
+    window.set_shrink_error(SharedString::new());
+    window.set_free_regions(ModelRc::from(regions.as_slice()));
+    window.set_free_space_error(SharedString::new());
+    window.set_unallocated_summary(SharedString::from(unallocated));
+}
+
+/// Show the size of the root partition in the Disks step size control.
+fn apply_size_control(window: &AppWindow, state: &Arc<RwLock<InstallerState>>) {
+    let size = state.read().target.as_ref().and_then(|plan| {
+        plan.partitions
+            .iter()
+            .find(|spec| {
+                spec.mountpoint
+                    .as_deref()
+                    .map(partition::normalize_mountpoint)
+                    == Some("/")
+            })
+            .map(|spec| spec.size.clone())
+    });
+    match size {
+        Some(PartitionSize::ExactBytes(bytes)) => {
+            window.set_root_size_mode(1);
+            window.set_root_size_gib(SharedString::from(format!(
+                "{:.1}",
+                bytes as f64 / GIB as f64
+            )));
+        }
+        Some(PartitionSize::Percentage(percent)) => {
+            window.set_root_size_mode(2);
+            window.set_root_size_percent(percent as f32);
+        }
+        Some(PartitionSize::Remainder) | None => window.set_root_size_mode(0),
+    }
+}
+
+/// Apply the size picked on the Disks step to the root partition. `mode` is
+/// the index in the size control: all remaining space, GiB or percentage.
+fn handle_root_size(
+    window: &AppWindow,
+    backend: &Backend,
+    state: &Arc<RwLock<InstallerState>>,
+    plan_store: &Arc<RwLock<Option<InstallPlan>>>,
+    mode: i32,
+    gib: &str,
+    percent: f32,
+) {
+    let size = match mode {
+        0 => Ok(PartitionSize::Remainder),
+        1 => gib
+            .trim()
+            .parse::<f64>()
+            .ok()
+            .filter(|gib| *gib > 0.0)
+            .map(|gib| PartitionSize::ExactBytes((gib * 1024.0).round() as u64 * MIB))
+            .context("enter the size in GiB, e.g. 40"),
+        _ => Ok(PartitionSize::Percentage(
+            percent.round().clamp(1.0, 100.0) as u8
+        )),
+    };
+
+    match size.and_then(|size| backend.set_root_size(size)) {
+        Ok(_) => {
+            window.set_partition_dry_run(SharedString::new());
+            window.set_disk_selection_summary(build_disk_summary(&state.read()));
+            apply_partition_editor(window, state);
+            refresh_install_plan(window, backend, plan_store);
+        }
+        Err(err) => window.set_unallocated_summary(SharedString::from(format!("{err:#}"))),
+    }
+}
+
+/// Rebuild the install plan after a layout change so the summary always
+/// matches what would run.
+fn refresh_install_plan(
+    window: &AppWindow,
+    backend: &Backend,
+    plan_store: &Arc<RwLock<Option<InstallPlan>>>,
+) {
+    match backend.prepare_plan() {
+        Ok(plan) => {
+            window.set_install_plan_summary(build_install_plan_summary(&plan));
+            plan_store.write().replace(plan);
+        }
+        Err(err) => {
+            plan_store.write().take();
+            window.set_install_plan_summary(SharedString::from(format!(
+                "The install plan cannot be built yet: {err:#}"
+            )));
+        }
+    }
+}
+
+/// Partitions on the selected disk that can be shrunk, as path and label.
+fn shrink_candidates(state: &InstallerState) -> Vec<(String, String)> {
+    state
+        .selected_disk
+        .as_ref()
+        .and_then(|disk| state.disk_inventory.find(&disk.path))
+        .map(|device| {
+            resize::shrink_candidates(device)
+                .into_iter()
+                .map(|node| {
+                    let mut label = format!(
+                        "{} ({}, {})",
+                        node.path,
+                        node.filesystem.as_deref().unwrap_or_default(),
+                        human_readable_bytes(node.size_bytes)
+                    );
+                    if let Some(name) = &node.label {
+                        label.push_str(&format!(" \"{name}\""));
+                    }
+                    (node.path.clone(), label)
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Before and after sizes of the partitions the plan shrinks.
+fn describe_shrink(plan: &DiskPlan) -> String {
+    if plan.resizes.is_empty() {
+        return String::new();
+    }
+
+    let mut lines = Vec::new();
+    for shrink in &plan.resizes {
+        lines.push(format!(
+            "{} ({}): {} now, {} after shrinking, freeing {}",
+            shrink.path,
+            shrink.filesystem.label(),
+            human_readable_bytes(shrink.current_bytes),
+            human_readable_bytes(shrink.new_bytes),
+            human_readable_bytes(shrink.freed_bytes())
+        ));
+    }
+    if let DiskMode::FreeSpace(region) = &plan.mode {
+        lines.push(format!(
+            "{} of free space for the new partitions",
+            human_readable_bytes(region.size_bytes())
+        ));
+    }
+    lines.push("The filesystem is checked and shrunk before its partition.".into());
+    lines.join("\n")
 }
 
-fn disk_to_item(disk: DiskIdentifier, selected_path: Option<&str>) -> DiskItem {
+/// Plan shrinking the chosen partition and show the resulting layout.
+fn handle_shrink(
+    window: &AppWindow,
+    backend: &Backend,
+    state: &Arc<RwLock<InstallerState>>,
+    plan_store: &Arc<RwLock<Option<InstallPlan>>>,
+    index: usize,
+    size: &str,
+) {
+    let path = shrink_candidates(&state.read())
+        .into_iter()
+        .nth(index)
+        .map(|(path, _)| path);
+    let result = path
+        .context("choose a partition to shrink")
+        .and_then(|path| match editor::parse_size(size)? {
+            PartitionSize::ExactBytes(bytes) => backend.shrink_partition(&path, bytes),
+            _ => anyhow::bail!("enter the new size of the partition, e.g. 120GiB"),
+        });
+
+    match result {
+        Ok(shrink) => {
+            info!(
+                path = shrink.path,
+                new_bytes = shrink.new_bytes,
+                "planned partition shrink"
+            );
+            window.set_partition_dry_run(SharedString::new());
+            window.set_disk_selection_summary(build_disk_summary(&state.read()));
+            apply_partition_editor(window, state);
+            apply_size_control(window, state);
+            refresh_install_plan(window, backend, plan_store);
+        }
+        Err(err) => window.set_shrink_error(SharedString::from(format!("{err:#}"))),
+    }
+}
+
+/// A free space region as offered in the Disks step, e.g. "120.0 GiB free
+/// at 250.0 GiB".
+fn describe_region(region: &FreeSpaceRegion) -> SharedString {
+    format!(
+        "{} free at {}",
+        human_readable_bytes(region.size_bytes()),
+        human_readable_bytes(region.start_bytes)
+    )
+    .into()
+}
+
+/// Lay out the new partitions in the chosen free space region.
+fn handle_free_space(
+    window: &AppWindow,
+    backend: &Backend,
+    state: &Arc<RwLock<InstallerState>>,
+    plan_store: &Arc<RwLock<Option<InstallPlan>>>,
+    index: usize,
+) {
+    match backend.use_free_space(index) {
+        Ok(region) => {
+            info!(
+                start_bytes = region.start_bytes,
+                end_bytes = region.end_bytes,
+                "installing into free space"
+            );
+            window.set_partition_dry_run(SharedString::new());
+            window.set_disk_selection_summary(build_disk_summary(&state.read()));
+            apply_partition_editor(window, state);
+            apply_size_control(window, state);
+            refresh_install_plan(window, backend, plan_store);
+        }
+        Err(err) => window.set_free_space_error(SharedString::from(format!("{err:#}"))),
+    }
+}
+
+/// Turn an edited editor field into a layout change.
+fn parse_field_edit(index: usize, field: &str, value: &str) -> Result<PartitionEdit> {
+    Ok(match field {
+        "mountpoint" => PartitionEdit::SetMountpoint {
+            index,
+            mountpoint: Some(value.trim().to_string()).filter(|m| !m.is_empty()),
+        },
+        "filesystem" => PartitionEdit::SetFilesystem {
+            index,
+            filesystem: editor::parse_filesystem(value)?,
+        },
+        "size" => PartitionEdit::SetSize {
+            index,
+            size: editor::parse_size(value)?,
+        },
+        "flags" => PartitionEdit::SetFlags {
+            index,
+            flags: editor::parse_flags(value),
+        },
+        other => anyhow::bail!("unknown partition field {other}"),
+    })
+}
+
+/// Apply a layout change through the backend. Rows are only rebuilt when
+/// `rebuild_rows` is set so that typing in a field keeps its cursor.
+fn handle_layout_edit(
+    window: &AppWindow,
+    backend: &Backend,
+    state: &Arc<RwLock<InstallerState>>,
+    plan_store: &Arc<RwLock<Option<InstallPlan>>>,
+    edit: Result<PartitionEdit>,
+    rebuild_rows: bool,
+) {
+    let result = edit.and_then(|edit| backend.edit_partitions(edit));
+    match result {
+        Ok(problems) => {
+            window.set_partition_dry_run(SharedString::new());
+            window.set_disk_selection_summary(build_disk_summary(&state.read()));
+            if rebuild_rows {
+                apply_partition_editor(window, state);
+            } else {
+                window.set_layout_problems(SharedString::from(describe_plan_errors(&problems)));
+            }
+            apply_size_control(window, state);
+            refresh_install_plan(window, backend, plan_store);
+        }
+        Err(err) => {
+            let mut problems = format!("- {err:#}");
+            let remaining = describe_plan_errors(&backend.validate_target());
+            if !remaining.is_empty() {
+                problems.push('\n');
+                problems.push_str(&remaining);
+            }
+            window.set_layout_problems(SharedString::from(problems));
+        }
+    }
+}
+
+/// Every problem with the layout, one per line.
+fn describe_plan_errors(errors: &[PlanError]) -> String {
+    errors
+        .iter()
+        .map(|error| format!("- {error}"))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Show what sfdisk would write, checked against the real device.
+fn apply_partition_dry_run(window: &AppWindow, backend: &Backend) {
+    let text = match backend.dry_run_partitioning() {
+        Ok(output) => format!("sfdisk dry run succeeded:\n{}", output.stdout.trim()),
+        Err(err) => {
+            warn!("partition dry run failed: {:#}", err);
+            format!("sfdisk dry run failed: {err:#}")
+        }
+    };
+    window.set_partition_dry_run(SharedString::from(text));
+}
+
+fn disk_to_item(device: &DiskDevice, selected_path: Option<&str>) -> DiskItem {
+    let disk = &device.identifier;
     let label = disk
         .label
         .as_ref()
@@ -225,12 +818,93 @@ fn disk_to_item(disk: DiskIdentifier, selected_path: Option<&str>) -> DiskItem {
 
     DiskItem {
         label: label.into(),
-        path: disk.path.into(),
+        path: disk.path.clone().into(),
         size,
+        details: describe_disk_contents(device),
+        warning: existing_systems_warning(device)
+            .map(SharedString::from)
+            .unwrap_or_default(),
+        eligible: device.is_eligible(),
+        reason: device
+            .ineligible
+            .as_ref()
+            .map(|reason| SharedString::from(format!("Not available: {}", reason.describe())))
+            .unwrap_or_default(),
         selected,
     }
 }
 
+fn describe_disk_contents(device: &DiskDevice) -> SharedString {
+    let mut parts = Vec::new();
+    if let Some(transport) = &device.transport {
+        parts.push(transport.to_uppercase());
+    }
+    if let Some(table) = &device.partition_table {
+        parts.push(table.to_uppercase());
+    }
+
+    let filesystems: Vec<String> = device
+        .partitions
+        .iter()
+        .map(|partition| {
+            partition
+                .filesystem
+                .clone()
+                .unwrap_or_else(|| "unformatted".into())
+        })
+        .collect();
+    match filesystems.len() {
+        0 => parts.push("no partitions".into()),
+        1 => parts.push(format!("1 partition ({})", filesystems[0])),
+        count => parts.push(format!("{count} partitions ({})", filesystems.join(", "))),
+    }
+
+    let free_bytes: u64 = device
+        .free_regions
+        .iter()
+        .map(|region| region.size_bytes())
+        .sum();
+    if free_bytes > 0 {
+        parts.push(format!("{} unallocated", human_readable_bytes(free_bytes)));
+    }
+
+    let geometry = &device.identifier.geometry;
+    if geometry.rotational {
+        parts.push("rotational".into());
+    }
+    if geometry.physical_sector_bytes != 512 {
+        parts.push(format!("{}-byte sectors", geometry.physical_sector_bytes));
+    }
+
+    if device.read_only {
+        parts.push("read-only".into());
+    }
+    if device.removable {
+        parts.push("removable".into());
+    }
+
+    SharedString::from(parts.join(" · "))
+}
+
+/// Warning listing what would be lost by installing onto `device`.
+fn existing_systems_warning(device: &DiskDevice) -> Option<String> {
+    if device.existing_systems.is_empty() {
+        return None;
+    }
+
+    let found: Vec<String> = device
+        .existing_systems
+        .iter()
+        .map(|system| system.describe())
+        .collect();
+    let object = if found.len() == 1 { "it" } else { "them" };
+    Some(format!(
+        "Warning: {} contains {}. Installing will erase {object}.",
+        device.identifier.path,
+        found.join(", ")
+    ))
+}
+
 fn format_size(bytes: u64) -> SharedString {
     const THRESHOLD: f64 = 1024.0;
     const UNITS: &[&str] = &["B", "KiB", "MiB", "GiB", "TiB"];
@@ -256,23 +930,36 @@ fn handle_disk_selection(
     plan_store: &Arc<RwLock<Option<InstallPlan>>>,
     index: usize,
 ) {
-    let disks = {
+    let inventory = {
         let mut guard = state.write();
-        if let Some(selected) = guard.discovered_disks.get(index).cloned() {
-            guard.selected_disk = Some(selected.clone());
-            guard.target = Some(partition::default_plan_for_disk(&selected));
-        } else {
-            warn!(index, count = guard.discovered_disks.len(), "disk selection index out of range");
+        let Some(device) = guard.disk_inventory.disks.get(index) else {
+            warn!(
+                index,
+                count = guard.disk_inventory.disks.len(),
+                "disk selection index out of range"
+            );
+            return;
+        };
+        if let Some(reason) = &device.ineligible {
+            warn!(
+                path = device.identifier.path,
+                reason = reason.describe(),
+                "ignoring selection of ineligible disk"
+            );
             return;
         }
-        guard.discovered_disks.clone()
+
+        let selected = device.identifier.clone();
+        guard.selected_disk = Some(selected.clone());
+        guard.target = Some(partition::default_plan_for_disk(&selected));
+        guard.disk_inventory.clone()
     };
 
     plan_store.write().take();
     window.set_install_plan_summary(SharedString::new());
     window.set_install_log(SharedString::new());
 
-    apply_disk_inventory(window, state, disks);
+    apply_disk_inventory(window, state, inventory);
 }
 
 fn append_log(window: &AppWindow, line: &str) {
@@ -292,8 +979,17 @@ fn build_disk_summary(state: &InstallerState) -> SharedString {
             plan.target.path,
             human_readable_bytes(plan.target.size_bytes)
         ));
-        for partition in &plan.partitions {
-            lines.push(format!("  - {}", partition::describe_partition(partition)));
+        // Sizes come from the computed ranges, so they match what is written.
+        let ranges = partition::compute_ranges(plan).ok();
+        for (index, spec) in plan.partitions.iter().enumerate() {
+            let description = partition::describe_partition(spec);
+            match ranges.as_ref().and_then(|ranges| ranges.get(index)) {
+                Some(range) => lines.push(format!(
+                    "  - {description}, {}",
+                    human_readable_bytes((range.end_mib - range.start_mib) * MIB)
+                )),
+                None => lines.push(format!("  - {description}")),
+            }
         }
         SharedString::from(lines.join("\n"))
     } else if let Some(disk) = state.selected_disk.as_ref() {
@@ -328,11 +1024,11 @@ fn build_install_plan_summary(plan: &InstallPlan) -> SharedString {
     lines.push("Installation plan:".to_string());
     for (idx, step) in plan.steps().iter().enumerate() {
         lines.push(format!(
-            "{}. {:?}: {} ({} commands)",
+            "{}. {:?}: {} ({} actions)",
             idx + 1,
             step.stage,
             step.summary,
-            step.commands.len()
+            step.actions.len()
         ));
     }
 
@@ -349,6 +1045,11 @@ fn default_steps() -> Vec<StepData> {
         .collect()
 }
 
+const FILESYSTEM_STEP: usize = 5;
+
+const MIB: u64 = 1024 * 1024;
+const GIB: u64 = 1024 * MIB;
+
 const WIZARD_STEPS: &[(&str, &str)] = &[
     ("Welcome", "Overview and prerequisites"),
     ("Locale", "Select language and formats"),